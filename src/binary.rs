@@ -0,0 +1,48 @@
+//! Encodings for binary data
+//!
+//! Byte strings are written as `xs:hexBinary` unless configured otherwise. A single field can
+//! pick its encoding by prefixing its tag name with `$hex:` or `$base64:`, before any `$attr:`,
+//! e.g. `$base64:{urn:ietf:params:xml:ns:epp-1.0}epp:data` or `$base64:$attr:digest`.
+
+use base64::Engine;
+
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub enum BinaryEncoding {
+    /// `xs:hexBinary`
+    #[default]
+    Hex,
+    /// `xs:base64Binary`
+    Base64,
+}
+
+impl BinaryEncoding {
+    /// Split the encoding prefix, if any, off a tag name
+    pub(crate) fn split_tag(tag: &str) -> (Option<Self>, &str) {
+        if let Some(tag) = tag.strip_prefix("$hex:") {
+            (Some(BinaryEncoding::Hex), tag)
+        } else if let Some(tag) = tag.strip_prefix("$base64:") {
+            (Some(BinaryEncoding::Base64), tag)
+        } else {
+            (None, tag)
+        }
+    }
+
+    pub(crate) fn encode(self, v: &[u8]) -> String {
+        match self {
+            BinaryEncoding::Hex => hex::encode(v),
+            BinaryEncoding::Base64 => base64::engine::general_purpose::STANDARD.encode(v),
+        }
+    }
+
+    /// Decode binary data, ignoring any whitespace in it
+    pub(crate) fn decode(self, s: &str) -> Option<Vec<u8>> {
+        let s = s
+            .chars()
+            .filter(|c| !c.is_ascii_whitespace())
+            .collect::<String>();
+        match self {
+            BinaryEncoding::Hex => hex::decode(s).ok(),
+            BinaryEncoding::Base64 => base64::engine::general_purpose::STANDARD.decode(s).ok(),
+        }
+    }
+}
@@ -1,22 +1,282 @@
 use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::VecDeque;
 use std::ops::{AddAssign, MulAssign};
+use std::rc::Rc;
 
-use crate::Tag;
+use crate::encoding::DecodingRead;
+use crate::{BinaryEncoding, Tag};
 use serde::de::IntoDeserializer;
+use serde::de::DeserializeOwned;
 use serde::{de, Deserialize};
 
 // TODO: revert Iterator<Item=XmlRes> to this if trait_alias stabilizes
 // pub trait XMLIter = Iterator<Item=xml::reader::Result<xml::reader::XmlEvent>>;
 type XmlRes = xml::reader::Result<xml::reader::XmlEvent>;
+/// Positions of the events read ahead of the deserializer, with the nodes skipped before each
+type Positions = Rc<RefCell<VecDeque<(xml::common::TextPosition, Vec<Skipped>)>>>;
+
+/// A node left out of the events `ReaderEvents` returns, kept for where it matters
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Skipped {
+    Comment(String),
+    ProcessingInstruction {
+        name: String,
+        data: Option<String>,
+    },
+    /// Whitespace between tags, or trimmed off the ends of text
+    Whitespace(String),
+}
 
-pub struct Deserializer<I: Iterator<Item = XmlRes>> {
+/// Deserializer reading from a stream of `xml-rs` events
+///
+/// `'de` is the lifetime of the document when it is given whole as a string or slice, which
+/// strings the parser passes on unchanged are borrowed from.
+pub struct Deserializer<'de, I: Iterator<Item = XmlRes>> {
     reader: itertools::MultiPeek<I>,
+    /// The document as a whole, when it is a string, for text to be borrowed from
+    input: Option<Input<'de>>,
+    /// Events read out of order, to be returned again before those still in `reader`
+    replay: VecDeque<(xml::reader::XmlEvent, Option<xml::common::TextPosition>)>,
+    replay_peeked: usize,
+    positions: Option<Positions>,
+    position: Option<xml::common::TextPosition>,
+    /// Nodes skipped before the event last returned by `next`
+    skipped: Vec<Skipped>,
+    path: Vec<String>,
     depth: u64,
     is_map_value: bool,
     is_seq_value: bool,
     is_greedy: bool,
     is_value: bool,
     reset_peek_offset: u64,
+    binary: BinaryEncoding,
+    options: Options,
+}
+
+/// A document given as a string, for text that the parser passes on unchanged to be borrowed
+/// from it rather than copied
+struct Input<'de> {
+    text: &'de str,
+    /// The last position looked up, to carry on from, as positions mostly come in order
+    row: u64,
+    column: u64,
+    offset: usize,
+}
+
+impl<'de> Input<'de> {
+    fn new(text: &'de str) -> Self {
+        Input {
+            text,
+            row: 0,
+            column: 0,
+            offset: 0,
+        }
+    }
+
+    /// The byte offset of a position from the parser, which counts lines and characters
+    fn offset(&mut self, position: xml::common::TextPosition) -> Option<usize> {
+        if (position.row, position.column) < (self.row, self.column) {
+            (self.row, self.column, self.offset) = (0, 0, 0);
+        }
+        while self.row < position.row {
+            self.offset += self.text[self.offset..].find('\n')? + 1;
+            self.row += 1;
+            self.column = 0;
+        }
+        let skip = (position.column - self.column) as usize;
+        if skip > 0 {
+            let (i, c) = self.text[self.offset..].char_indices().nth(skip - 1)?;
+            self.offset += i + c.len_utf8();
+            self.column = position.column;
+        }
+        Some(self.offset)
+    }
+
+    /// The text of an event at `position`, if it is in the input as it is in `s`
+    fn text(&mut self, position: xml::common::TextPosition, s: &str) -> Option<&'de str> {
+        let rest = &self.text[self.offset(position)?..];
+        // Leading whitespace may have been trimmed, and CDATA starts with its markup
+        [Some(rest), Some(rest.trim_start()), rest.strip_prefix("<![CDATA[")]
+            .into_iter()
+            .flatten()
+            .find_map(|rest| rest.get(..s.len()).filter(|text| *text == s))
+    }
+
+    /// An attribute value of the start tag at `position`, if it is in the tag as it is in `s`
+    fn attribute(&mut self, position: xml::common::TextPosition, s: &str) -> Option<&'de str> {
+        // The parser puts the root element at the end of its tag when there is no declaration,
+        // and `<` cannot appear in attribute values, so look back for where the tag starts
+        let offset = self.offset(position)?;
+        let start = match self.text[offset..].starts_with('<') {
+            true => offset,
+            false => self.text[..offset].rfind('<')?,
+        };
+        let tag = &self.text[start..];
+        let mut quote = None;
+        let end = tag.bytes().position(|b| match (quote, b) {
+            (None, b'"' | b'\'') => {
+                quote = Some(b);
+                false
+            }
+            (Some(q), b) if b == q => {
+                quote = None;
+                false
+            }
+            (quote, b) => quote.is_none() && b == b'>',
+        })?;
+        // Any match will do, as it is the same text wherever it is
+        let tag = &tag[..end];
+        tag.find(s).map(|start| &tag[start..start + s.len()])
+    }
+}
+
+const XSI_NS: &str = "http://www.w3.org/2001/XMLSchema-instance";
+const XML_NS: &str = "http://www.w3.org/XML/1998/namespace";
+
+/// What to do with elements and attributes that match no field of the struct being deserialized
+///
+/// Attributes in the `xsi` namespace are never considered unknown.
+#[derive(Clone, Default)]
+pub enum UnknownFields {
+    /// Skip over them
+    #[default]
+    Ignore,
+    /// Skip over them, passing the error `Deny` would have raised to a callback
+    Report(std::sync::Arc<dyn Fn(&crate::Error) + Send + Sync>),
+    /// Fail with `Error::UnknownElement` or `Error::UnknownAttribute`
+    Deny,
+}
+
+impl std::fmt::Debug for UnknownFields {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UnknownFields::Ignore => f.write_str("Ignore"),
+            UnknownFields::Report(_) => f.write_str("Report(..)"),
+            UnknownFields::Deny => f.write_str("Deny"),
+        }
+    }
+}
+
+/// Options for the deserializer
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// Trim whitespace around text, except inside elements with `xml:space="preserve"`; text
+    /// that is only whitespace is dropped unless preserved
+    pub trim_whitespace: bool,
+    /// Replace references to undefined entities with U+FFFD rather than failing
+    pub replace_unknown_entity_references: bool,
+    /// Read CDATA sections as ordinary text, to be joined with the text around them
+    pub cdata_to_characters: bool,
+    /// Join adjacent runs of text into one event; string values are read across runs either way
+    pub coalesce_characters: bool,
+    /// Drop comments, rather than keeping them in markup read into string fields; `RawXml` keeps
+    /// them either way
+    pub ignore_comments: bool,
+    /// Encoding of byte strings in fields without a `$hex:` or `$base64:` tag prefix
+    pub binary_encoding: BinaryEncoding,
+    /// What to do with elements and attributes that match no field, skipping them by default
+    pub unknown_fields: UnknownFields,
+    /// Collect every element with the name of a sequence field into it, even when other
+    /// elements lie between them, rather than stopping at the first one in the way
+    pub interleaved_sequences: bool,
+    /// Fail on elements nested deeper than this, counting the root element as depth 1
+    ///
+    /// Checked on each start tag as the parser hands it over, before it is buffered.
+    pub max_depth: Option<usize>,
+    /// Fail on elements with more attributes than this, namespace declarations aside
+    ///
+    /// Checked on each start tag as the parser hands it over, before it is buffered. The parser
+    /// has already read the whole tag into memory by then.
+    pub max_attributes: Option<usize>,
+    /// Fail on runs of text, whitespace or CDATA, or on comments, longer than this many bytes
+    ///
+    /// Checked on each run as the parser hands it over, before it is buffered. The parser has
+    /// already read the whole run into memory by then, so this does not bound memory use; use
+    /// `max_input_bytes` for that.
+    pub max_text_length: Option<usize>,
+    /// Fail once more than this many bytes have been read from the input
+    ///
+    /// This is checked as the parser reads, so it is what bounds the memory the parser may use.
+    pub max_input_bytes: Option<usize>,
+    /// Set the parser's own limit on how many characters entities declared in the document's DTD
+    /// may expand to still to be parsed at once, a million by default
+    ///
+    /// Going over it, or nesting entities too deeply, fails with `Error::LimitExceeded`.
+    pub max_entity_expansion: Option<usize>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            trim_whitespace: true,
+            replace_unknown_entity_references: true,
+            cdata_to_characters: false,
+            coalesce_characters: true,
+            ignore_comments: true,
+            binary_encoding: BinaryEncoding::Hex,
+            unknown_fields: UnknownFields::Ignore,
+            interleaved_sequences: false,
+            max_depth: None,
+            max_attributes: None,
+            max_text_length: None,
+            max_input_bytes: None,
+            max_entity_expansion: None,
+        }
+    }
+}
+
+/// An `io::Read` that fails once more than `max` bytes have been read through it
+struct LimitedRead<R: std::io::Read> {
+    inner: R,
+    read: usize,
+    max: Option<usize>,
+}
+
+/// A limit in `Options` gone over while reading, passed through the parser as an IO error to be
+/// picked out again by `reader_error`
+#[derive(Debug)]
+struct LimitError {
+    limit: &'static str,
+    max: usize,
+}
+
+impl std::fmt::Display for LimitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} of {} exceeded", self.limit, self.max)
+    }
+}
+
+impl std::error::Error for LimitError {}
+
+fn check_limit(limit: &'static str, max: Option<usize>, found: usize) -> Result<(), LimitError> {
+    match max {
+        Some(max) if found > max => Err(LimitError { limit, max }),
+        _ => Ok(()),
+    }
+}
+
+impl<R: std::io::Read> std::io::Read for LimitedRead<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = match self.max {
+            Some(max) if self.read >= max => {
+                // Only fail if there is more to the input than the limit
+                let mut byte = [0; 1];
+                if self.inner.read(&mut byte)? == 0 {
+                    return Ok(0);
+                }
+                return Err(std::io::Error::other(LimitError {
+                    limit: "max_input_bytes",
+                    max,
+                }));
+            }
+            Some(max) => buf.len().min(max - self.read),
+            None => buf.len(),
+        };
+        let read = self.inner.read(&mut buf[..len])?;
+        self.read += read;
+        Ok(read)
+    }
 }
 
 fn new_reader<I: IntoIterator<Item = XmlRes>>(
@@ -34,59 +294,375 @@ fn new_reader<I: IntoIterator<Item = XmlRes>>(
     }))
 }
 
-pub fn from_str<'a, T: Deserialize<'a>>(input: &'a str) -> crate::Result<T> {
-    from_bytes(input.as_bytes())
+/// An event read by `ReaderEvents`, with its position and the nodes skipped before it
+type ReadEvent = (XmlRes, xml::common::TextPosition, Vec<Skipped>);
+
+/// Events of a document being read from an `io::Read`, without processing instructions,
+/// comments or whitespace between elements
+///
+/// The position of every event is queued for `Deserializer::next` to pick up once the event is
+/// consumed, as the deserializer usually reads ahead of what it has consumed, along with the
+/// nodes left out before it.
+///
+/// Text is trimmed here rather than by the parser, so that it can be left as it is inside
+/// elements with `xml:space="preserve"`. There, whitespace is only dropped when it lies between
+/// two tags other than the start and end of the same element.
+pub struct ReaderEvents<R: std::io::Read> {
+    reader: xml::reader::EventReader<DecodingRead<LimitedRead<R>>>,
+    positions: Positions,
+    comments: Vec<Skipped>,
+    /// Nodes skipped before events that were dropped, to go with the next one returned
+    dropped: Vec<Skipped>,
+    /// Event read ahead to decide whether to keep the whitespace before it
+    ahead: Option<ReadEvent>,
+    /// Whether whitespace is preserved, for each open element
+    preserve: Vec<bool>,
+    trim_whitespace: bool,
+    after_start: bool,
+    after_text: bool,
+    finished: bool,
+    limits: Limits,
+    /// How many elements the parser has open
+    depth: usize,
 }
 
-pub fn from_string<'a, T: Deserialize<'a>>(input: String) -> crate::Result<T> {
-    from_bytes(input.as_bytes())
+/// The limits in `Options` that `ReaderEvents` checks
+struct Limits {
+    max_depth: Option<usize>,
+    max_attributes: Option<usize>,
+    max_text_length: Option<usize>,
+    max_entity_expansion: Option<usize>,
 }
 
-fn from_bytes<'a, T: Deserialize<'a>>(input: &[u8]) -> crate::Result<T> {
-    let config = xml::ParserConfig::new()
-        .trim_whitespace(true)
-        .whitespace_to_characters(true)
-        .replace_unknown_entity_references(true);
+impl<R: std::io::Read> ReaderEvents<R> {
+    /// Check an event against the limits in `Options`, before it goes anywhere else
+    fn check_limits(&mut self, event: XmlRes) -> Result<XmlRes, LimitError> {
+        match &event {
+            Ok(xml::reader::XmlEvent::StartElement { attributes, .. }) => {
+                self.depth += 1;
+                check_limit("max_depth", self.limits.max_depth, self.depth)?;
+                check_limit(
+                    "max_attributes",
+                    self.limits.max_attributes,
+                    attributes.len(),
+                )?;
+            }
+            Ok(xml::reader::XmlEvent::EndElement { .. }) => self.depth -= 1,
+            Ok(
+                xml::reader::XmlEvent::Characters(s)
+                | xml::reader::XmlEvent::CData(s)
+                | xml::reader::XmlEvent::Whitespace(s)
+                | xml::reader::XmlEvent::Comment(s),
+            ) => {
+                check_limit("max_text_length", self.limits.max_text_length, s.len())?;
+            }
+            // The parser only says in words which of its errors this is
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    xml::reader::ErrorKind::Syntax(msg) if msg == "Entity too big"
+                ) =>
+            {
+                if let Some(max) = self.limits.max_entity_expansion {
+                    return Err(LimitError {
+                        limit: "max_entity_expansion",
+                        max,
+                    });
+                }
+            }
+            _ => {}
+        }
+        Ok(event)
+    }
 
-    let mut event_reader = xml::reader::EventReader::new_with_config(input, config);
+    fn read(&mut self) -> Option<ReadEvent> {
+        use xml::common::Position;
 
-    match event_reader.next()? {
-        xml::reader::XmlEvent::StartDocument {
-            version,
-            encoding,
-            standalone,
-        } => {
-            trace!(
-                "start_document({:?}, {:?}, {:?})",
+        loop {
+            if self.finished {
+                return None;
+            }
+            let event = self.reader.next();
+            self.finished = matches!(event, Ok(xml::reader::XmlEvent::EndDocument) | Err(_));
+            let event = match self.check_limits(event) {
+                Err(err) => {
+                    self.finished = true;
+                    Err(std::io::Error::other(err).into())
+                }
+                Ok(event) => event,
+            };
+            match event {
+                Ok(xml::reader::XmlEvent::ProcessingInstruction { name, data }) => {
+                    self.comments
+                        .push(Skipped::ProcessingInstruction { name, data });
+                    continue;
+                }
+                Ok(xml::reader::XmlEvent::Comment(comment)) => {
+                    self.comments.push(Skipped::Comment(comment));
+                    continue;
+                }
+                _ => {}
+            }
+            let comments = std::mem::take(&mut self.comments);
+            return Some((event, self.reader.position(), comments));
+        }
+    }
+}
+
+impl<R: std::io::Read> Iterator for ReaderEvents<R> {
+    type Item = XmlRes;
+
+    fn next(&mut self) -> Option<XmlRes> {
+        loop {
+            let (event, position, mut comments) = match self.ahead.take() {
+                Some(ahead) => ahead,
+                None => self.read()?,
+            };
+            // Whitespace trimmed off the end of text goes with the event after it
+            let mut trailing = None;
+            let preserve = self.preserve.last().copied().unwrap_or(false);
+            let event = match event {
+                Ok(xml::reader::XmlEvent::StartElement { ref attributes, .. }) => {
+                    let space = attributes.iter().find(|a| {
+                        a.name.prefix.as_deref() == Some("xml") && a.name.local_name == "space"
+                    });
+                    self.preserve.push(match space {
+                        Some(space) => space.value == "preserve",
+                        None => preserve,
+                    });
+                    event
+                }
+                Ok(xml::reader::XmlEvent::EndElement { .. }) => {
+                    self.preserve.pop();
+                    event
+                }
+                Ok(xml::reader::XmlEvent::Characters(s)) if self.trim_whitespace && !preserve => {
+                    let trimmed = s.trim();
+                    if trimmed.is_empty() {
+                        self.dropped.extend(comments);
+                        self.dropped.push(Skipped::Whitespace(s));
+                        continue;
+                    }
+                    if trimmed.len() == s.len() {
+                        Ok(xml::reader::XmlEvent::Characters(s))
+                    } else {
+                        let start = s.len() - s.trim_start().len();
+                        let end = start + trimmed.len();
+                        if start > 0 {
+                            comments.push(Skipped::Whitespace(s[..start].to_string()));
+                        }
+                        if end < s.len() {
+                            trailing = Some(Skipped::Whitespace(s[end..].to_string()));
+                        }
+                        Ok(xml::reader::XmlEvent::Characters(trimmed.to_string()))
+                    }
+                }
+                Ok(xml::reader::XmlEvent::Whitespace(s)) if preserve => {
+                    let ahead = self.read();
+                    let before_text = matches!(
+                        ahead,
+                        Some((
+                            Ok(xml::reader::XmlEvent::Characters(_)
+                                | xml::reader::XmlEvent::CData(_)),
+                            ..
+                        ))
+                    );
+                    let before_end = matches!(
+                        ahead,
+                        Some((Ok(xml::reader::XmlEvent::EndElement { .. }), ..))
+                    );
+                    self.ahead = ahead;
+                    if !(self.after_text || before_text || (self.after_start && before_end)) {
+                        self.dropped.extend(comments);
+                        self.dropped.push(Skipped::Whitespace(s));
+                        continue;
+                    }
+                    Ok(xml::reader::XmlEvent::Characters(s))
+                }
+                Ok(xml::reader::XmlEvent::Whitespace(s)) => {
+                    self.dropped.extend(comments);
+                    self.dropped.push(Skipped::Whitespace(s));
+                    continue;
+                }
+                event => event,
+            };
+            self.after_start = matches!(event, Ok(xml::reader::XmlEvent::StartElement { .. }));
+            self.after_text = matches!(
+                event,
+                Ok(xml::reader::XmlEvent::Characters(_) | xml::reader::XmlEvent::CData(_))
+            );
+            let mut skipped = std::mem::take(&mut self.dropped);
+            skipped.extend(comments);
+            self.positions.borrow_mut().push_back((position, skipped));
+            self.dropped.extend(trailing);
+            return Some(event);
+        }
+    }
+}
+
+impl<'de, R: std::io::Read> Deserializer<'de, ReaderEvents<R>> {
+    /// Start deserializing a document from an `io::Read`, reading up to its root element
+    pub fn new(reader: R, options: Options) -> crate::Result<Self> {
+        Self::with_encoding(reader, options, true)
+    }
+
+    /// Start deserializing a document, working out its encoding from its first bytes only if
+    /// `sniff` is set, and otherwise reading it as UTF-8 whatever it declares
+    fn with_encoding(reader: R, options: Options, sniff: bool) -> crate::Result<Self> {
+        let mut config: xml::reader::ParserConfig2 = xml::ParserConfig::new()
+            .replace_unknown_entity_references(options.replace_unknown_entity_references)
+            .cdata_to_characters(options.cdata_to_characters)
+            .coalesce_characters(options.coalesce_characters)
+            // Comments are always read, as `RawXml` keeps them
+            .ignore_comments(false)
+            .into();
+        if let Some(max) = options.max_entity_expansion {
+            config = config.max_entity_expansion_length(max);
+        }
+
+        let reader = LimitedRead {
+            inner: reader,
+            read: 0,
+            max: options.max_input_bytes,
+        };
+        let reader = match sniff {
+            true => DecodingRead::new(reader).map_err(|err| reader_error(&err.into()))?,
+            false => DecodingRead::utf8(reader),
+        };
+        let encoding = match sniff {
+            true => reader.parser_encoding(),
+            false => Some(xml::Encoding::Utf8),
+        };
+        if encoding.is_some() {
+            config = config
+                .override_encoding(encoding)
+                .ignore_invalid_encoding_declarations(true);
+        }
+        let mut event_reader = xml::reader::EventReader::new_with_config(reader, config);
+
+        let start = event_reader
+            .next()
+            .map_err(|err| reader_error(&err))?;
+        match start {
+            xml::reader::XmlEvent::StartDocument {
                 version,
                 encoding,
-                standalone
-            );
+                standalone,
+            } => {
+                trace!(
+                    "start_document({:?}, {:?}, {:?})",
+                    version,
+                    encoding,
+                    standalone
+                );
+            }
+            _ => return Err(crate::Error::ExpectedElement),
         }
-        _ => return Err(crate::Error::ExpectedElement),
+
+        let positions = Positions::default();
+        let events = ReaderEvents {
+            reader: event_reader,
+            positions: positions.clone(),
+            comments: vec![],
+            dropped: vec![],
+            ahead: None,
+            preserve: vec![],
+            trim_whitespace: options.trim_whitespace,
+            after_start: false,
+            after_text: false,
+            finished: false,
+            limits: Limits {
+                max_depth: options.max_depth,
+                max_attributes: options.max_attributes,
+                max_text_length: options.max_text_length,
+                max_entity_expansion: options.max_entity_expansion,
+            },
+            depth: 0,
+        };
+        Ok(Deserializer::with_reader(
+            itertools::multipeek(events),
+            Some(positions),
+            options,
+        ))
     }
+}
 
-    let mut deserializer = Deserializer {
-        reader: new_reader(event_reader),
-        depth: 0,
-        is_map_value: false,
-        is_seq_value: false,
-        is_greedy: true,
-        is_value: false,
-        reset_peek_offset: 0,
-    };
+/// Deserialize from a string, which is read as UTF-8 whatever encoding its declaration names
+///
+/// Text and attribute values that appear in the input as they are, without references or
+/// changes to whitespace, can be borrowed by `&str` and `Cow<str>` fields.
+pub fn from_str<'a, T: Deserialize<'a>>(input: &'a str) -> crate::Result<T> {
+    from_str_custom(input, Options::default())
+}
+
+/// Deserialize from a string, with custom options
+pub fn from_str_custom<'a, T: Deserialize<'a>>(
+    input: &'a str,
+    options: Options,
+) -> crate::Result<T> {
+    let mut deserializer = Deserializer::with_encoding(input.as_bytes(), options, false)?;
+    deserializer.input = Some(Input::new(input));
+
+    T::deserialize(&mut deserializer).map_err(|err| deserializer.locate(err))
+}
+
+pub fn from_string<T: DeserializeOwned>(input: String) -> crate::Result<T> {
+    let mut deserializer = Deserializer::with_encoding(input.as_bytes(), Options::default(), false)?;
+
+    T::deserialize(&mut deserializer).map_err(|err| deserializer.locate(err))
+}
+
+/// Deserialize from bytes, in the encoding they declare
+///
+/// As with `from_str`, text in UTF-8 input can be borrowed where it appears unchanged.
+pub fn from_bytes<'a, T: Deserialize<'a>>(input: &'a [u8]) -> crate::Result<T> {
+    let mut deserializer = Deserializer::new(input, Options::default())?;
+    // Only text that matches the input is borrowed, so other encodings never will be
+    deserializer.input = std::str::from_utf8(input).ok().map(Input::new);
+
+    T::deserialize(&mut deserializer).map_err(|err| deserializer.locate(err))
+}
+
+pub fn from_slice<'a, T: Deserialize<'a>>(input: &'a [u8]) -> crate::Result<T> {
+    from_bytes(input)
+}
+
+/// Deserialize straight from an `io::Read`, pulling events off the reader as they are needed
+/// rather than reading the whole document up front
+///
+/// The parser reads in small chunks, so wrap files and sockets in an `io::BufReader`.
+pub fn from_reader<'a, R: std::io::Read, T: Deserialize<'a>>(reader: R) -> crate::Result<T> {
+    from_reader_custom(reader, Options::default())
+}
 
-    T::deserialize(&mut deserializer)
+/// Deserialize straight from an `io::Read`, with custom options
+pub fn from_reader_custom<'a, R: std::io::Read, T: Deserialize<'a>>(
+    reader: R,
+    options: Options,
+) -> crate::Result<T> {
+    let mut deserializer = Deserializer::new(reader, options)?;
+
+    T::deserialize(&mut deserializer).map_err(|err| deserializer.locate(err))
 }
 
 pub fn from_events<'a, T: Deserialize<'a>>(
     events: &[xml::reader::Result<xml::reader::XmlEvent>],
+) -> crate::Result<T> {
+    from_event_iter(events.iter().cloned())
+}
+
+/// Deserialize from events taken one at a time, e.g. from an `xml::reader::EventReader`
+///
+/// The events may start with `StartDocument`, or with the first element to deserialize, which
+/// allows decoding an element out of the middle of a document read by hand. Events are read
+/// ahead, so the iterator may be left past the end of the value.
+pub fn from_event_iter<'a, T: Deserialize<'a>, E: IntoIterator<Item = XmlRes>>(
+    events: E,
 ) -> crate::Result<T> {
     let mut reader = new_reader(
         events
-            .iter()
-            .filter(|event| !matches!(event, Ok(xml::reader::XmlEvent::Whitespace(_))))
-            .map(|event| event.to_owned()),
+            .into_iter()
+            .filter(|event| !matches!(event, Ok(xml::reader::XmlEvent::Whitespace(_)))),
     );
 
     if let Ok(xml::reader::XmlEvent::StartDocument { .. }) =
@@ -111,20 +687,37 @@ pub fn from_events<'a, T: Deserialize<'a>>(
 
     reader.reset_peek();
 
-    let mut deserializer = Deserializer {
-        reader,
-        depth: 0,
-        is_map_value: false,
-        is_seq_value: false,
-        is_greedy: true,
-        is_value: false,
-        reset_peek_offset: 0,
-    };
+    let mut deserializer = Deserializer::with_reader(reader, None, Options::default());
 
-    T::deserialize(&mut deserializer)
+    T::deserialize(&mut deserializer).map_err(|err| deserializer.locate(err))
 }
 
-impl<I: Iterator<Item = XmlRes>> Deserializer<I> {
+impl<'de, I: Iterator<Item = XmlRes>> Deserializer<'de, I> {
+    fn with_reader(
+        reader: itertools::MultiPeek<I>,
+        positions: Option<Positions>,
+        options: Options,
+    ) -> Self {
+        Deserializer {
+            reader,
+            input: None,
+            replay: VecDeque::new(),
+            replay_peeked: 0,
+            positions,
+            position: None,
+            skipped: vec![],
+            path: vec![],
+            depth: 0,
+            is_map_value: false,
+            is_seq_value: false,
+            is_greedy: true,
+            is_value: false,
+            reset_peek_offset: 0,
+            binary: options.binary_encoding,
+            options,
+        }
+    }
+
     fn set_map_value(&mut self) {
         trace!("set_map_value()");
         self.is_map_value = true;
@@ -168,12 +761,18 @@ impl<I: Iterator<Item = XmlRes>> Deserializer<I> {
     }
 
     fn peek(&mut self) -> crate::Result<&xml::reader::XmlEvent> {
+        if self.replay_peeked < self.replay.len() {
+            self.replay_peeked += 1;
+            let next = &self.replay[self.replay_peeked - 1].0;
+            trace!("peek() -> {:?}", next);
+            return Ok(next);
+        }
         let next = match match self.reader.peek() {
             Some(n) => n,
             None => return Ok(&xml::reader::XmlEvent::EndDocument),
         } {
             Ok(n) => n,
-            Err(e) => return Err(e.into()),
+            Err(e) => return Err(reader_error(e)),
         };
         trace!("peek() -> {:?}", next);
         Ok(next)
@@ -181,23 +780,47 @@ impl<I: Iterator<Item = XmlRes>> Deserializer<I> {
 
     fn reset_peek(&mut self) {
         trace!("reset_peek()");
+        self.replay_peeked = 0;
         self.reader.reset_peek();
         for _ in 0..self.reset_peek_offset {
-            self.reader.peek();
+            let _ = self.peek();
         }
     }
 
     fn next(&mut self) -> crate::Result<xml::reader::XmlEvent> {
-        let next = match self.reader.next() {
-            Some(n) => n,
-            None => return Err(crate::Error::ExpectedElement),
-        }?;
+        self.replay_peeked = 0;
+        let next = match self.replay.pop_front() {
+            Some((next, position)) => {
+                self.reader.reset_peek();
+                self.position = position;
+                self.skipped.clear();
+                next
+            }
+            None => {
+                let next = match self.reader.next() {
+                    Some(n) => n,
+                    None => return Err(crate::Error::ExpectedElement),
+                };
+                if let Some(positions) = &self.positions {
+                    if let Some((position, skipped)) = positions.borrow_mut().pop_front() {
+                        self.position = Some(position);
+                        self.skipped = skipped;
+                    }
+                }
+                next.map_err(|err| reader_error(&err))?
+            }
+        };
         match next {
-            xml::reader::XmlEvent::StartElement { .. } => {
+            xml::reader::XmlEvent::StartElement { ref name, .. } => {
                 self.depth += 1;
+                self.path.push(match &name.prefix {
+                    Some(prefix) => format!("{}:{}", prefix, name.local_name),
+                    None => name.local_name.clone(),
+                });
             }
             xml::reader::XmlEvent::EndElement { .. } => {
                 self.depth -= 1;
+                self.path.pop();
             }
             _ => {}
         }
@@ -205,6 +828,168 @@ impl<I: Iterator<Item = XmlRes>> Deserializer<I> {
         Ok(next)
     }
 
+    /// Attach the current position and element path to an error
+    fn locate(&self, error: crate::Error) -> crate::Error {
+        if let crate::Error::Located { .. } = error {
+            return error;
+        }
+        crate::Error::Located {
+            position: self.position,
+            path: format!("/{}", self.path.join("/")),
+            error: Box::new(error),
+        }
+    }
+
+    /// The position of the event `peek` would return next
+    fn peek_position(&self) -> Option<xml::common::TextPosition> {
+        let offset = self.reset_peek_offset as usize;
+        if let Some((_, position)) = self.replay.get(offset) {
+            return *position;
+        }
+        match &self.positions {
+            Some(positions) => positions
+                .borrow()
+                .get(offset - self.replay.len())
+                .map(|(position, _)| *position),
+            None => self.position,
+        }
+    }
+
+    /// The whitespace left out before the event `peek` would return next, once it has been peeked
+    fn peek_whitespace(&self) -> String {
+        let offset = self.reset_peek_offset as usize;
+        let positions = match &self.positions {
+            Some(positions) if offset >= self.replay.len() => positions,
+            _ => return String::new(),
+        };
+        let positions = positions.borrow();
+        let skipped = match positions.get(offset - self.replay.len()) {
+            Some((_, skipped)) => skipped,
+            None => return String::new(),
+        };
+        skipped
+            .iter()
+            .filter_map(|skipped| match skipped {
+                Skipped::Whitespace(s) => Some(s.as_str()),
+                Skipped::Comment(_) | Skipped::ProcessingInstruction { .. } => None,
+            })
+            .collect()
+    }
+
+    /// Put events taken out of the stream by `next` back in front of it, to be read again
+    fn unread(&mut self, events: Vec<(xml::reader::XmlEvent, Option<xml::common::TextPosition>)>) {
+        for event in events.into_iter().rev() {
+            self.replay.push_front(event);
+        }
+        self.reset_peek();
+    }
+
+    /// Deal with an element or attribute that matches no field, as set by
+    /// `Options::unknown_fields`
+    fn unknown(
+        &self,
+        error: crate::Error,
+        position: Option<xml::common::TextPosition>,
+    ) -> crate::Result<()> {
+        let error = crate::Error::Located {
+            position,
+            path: format!("/{}", self.path.join("/")),
+            error: Box::new(error),
+        };
+        match &self.options.unknown_fields {
+            UnknownFields::Ignore => Ok(()),
+            UnknownFields::Report(report) => {
+                report(&error);
+                Ok(())
+            }
+            UnknownFields::Deny => Err(error),
+        }
+    }
+
+    /// Read down to the element at `path`, and iterate over its child elements one at a time
+    ///
+    /// Each segment of `path` is a tag as used in field names, e.g. `{urn:example}list`, and
+    /// matches the first element with that local name and namespace among the children of the
+    /// previous one; siblings before it are skipped. Every item is decoded from one child element
+    /// as a struct field would be, so only one is ever held in memory.
+    pub fn children_at<T: de::DeserializeOwned>(
+        &mut self,
+        path: &[&str],
+    ) -> crate::Result<Children<'_, 'de, I, T>> {
+        trace!("children_at({:?})", path);
+        self.step_into(path)?;
+        Ok(Children {
+            de: self,
+            done: false,
+            item: std::marker::PhantomData,
+        })
+    }
+
+    /// Read down to the element at `path`, as `children_at` does, and stop just inside it
+    pub fn step_into(&mut self, path: &[&str]) -> crate::Result<()> {
+        trace!("step_into({:?})", path);
+        for segment in path {
+            let tag = Tag::new(segment);
+            loop {
+                match self.peek()? {
+                    xml::reader::XmlEvent::StartElement { name, .. }
+                        if name.local_name == tag.e
+                            && (tag.n.is_none() || name.namespace.as_deref() == tag.n) =>
+                    {
+                        self.reset_peek();
+                        self.next().map_err(|err| self.locate(err))?;
+                        break;
+                    }
+                    xml::reader::XmlEvent::StartElement { .. } => {
+                        self.reset_peek();
+                        self.step_over().map_err(|err| self.locate(err))?;
+                    }
+                    xml::reader::XmlEvent::EndElement { .. }
+                    | xml::reader::XmlEvent::EndDocument => {
+                        self.reset_peek();
+                        return Err(self.locate(crate::Error::ExpectedElement));
+                    }
+                    _ => {
+                        self.reset_peek();
+                        self.next().map_err(|err| self.locate(err))?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Decode the next element at the current level, leaving the deserializer just after it
+    ///
+    /// Text before the element is skipped. Returns `None` at the end of the parent element or of
+    /// the document, without consuming either. Nothing past the end of the element returned is
+    /// read, so elements can be taken one at a time from a stream whose root is never closed,
+    /// such as an XMPP stream, after stepping inside the root with `step_into`.
+    pub fn next_element<T: de::DeserializeOwned>(&mut self) -> crate::Result<Option<T>> {
+        trace!("next_element()");
+        loop {
+            let is_start = match self.peek() {
+                Ok(xml::reader::XmlEvent::StartElement { .. }) => true,
+                Ok(
+                    xml::reader::XmlEvent::EndElement { .. } | xml::reader::XmlEvent::EndDocument,
+                ) => {
+                    self.reset_peek();
+                    return Ok(None);
+                }
+                Ok(_) => false,
+                Err(err) => return Err(self.locate(err)),
+            };
+            self.reset_peek();
+            if is_start {
+                self.set_map_value();
+                return T::deserialize(&mut *self)
+                    .map(Some)
+                    .map_err(|err| self.locate(err));
+            }
+            self.next().map_err(|err| self.locate(err))?;
+        }
+    }
+
     fn read_inner_value<T, F: FnOnce(&mut Self) -> crate::Result<T>>(
         &mut self,
         f: F,
@@ -261,7 +1046,10 @@ impl<I: Iterator<Item = XmlRes>> Deserializer<I> {
                 if name == old_name {
                     Ok(())
                 } else {
-                    Err(crate::Error::ExpectedElement)
+                    Err(crate::Error::MismatchedElement {
+                        expected: Box::new(old_name),
+                        found: Box::new(name),
+                    })
                 }
             }
             _ => Err(crate::Error::ExpectedElement),
@@ -301,82 +1089,195 @@ impl<I: Iterator<Item = XmlRes>> Deserializer<I> {
 
     fn parse_string(&mut self) -> crate::Result<String> {
         trace!("parse_string()");
-        self.read_inner_value(|this| {
-            if let xml::reader::XmlEvent::EndElement { .. } = this.peek()? {
-                return Ok(String::new());
-            }
+        self.parse_text(Ok)
+    }
 
-            match this.next()? {
-                xml::reader::XmlEvent::CData(s) | xml::reader::XmlEvent::Characters(s) => Ok(s),
-                xml::reader::XmlEvent::StartElement {
-                    name,
-                    attributes,
-                    namespace,
-                } => {
-                    let mut output: Vec<u8> = Vec::new();
-                    let conf = xml::writer::EmitterConfig::new()
-                        .perform_indent(false)
-                        .write_document_declaration(false)
-                        .normalize_empty_elements(true)
-                        .cdata_to_characters(false)
-                        .keep_element_names_stack(false)
-                        .pad_self_closing(false);
-                    let mut writer = conf.create_writer(&mut output);
-                    writer
-                        .write(xml::writer::XmlEvent::StartElement {
-                            name: name.borrow(),
-                            attributes: attributes.iter().map(|a| a.borrow()).collect(),
-                            namespace: std::borrow::Cow::Borrowed(&namespace),
-                        })
-                        .unwrap();
-                    let depth = this.depth - 1;
-                    loop {
-                        let event = this.next()?;
-                        trace!("{:?}; {}; {}", event, this.depth, depth);
-                        if this.depth == depth {
-                            break;
+    /// Read the text of the current value and convert it while its element is still open, so
+    /// that conversion errors point into the element
+    fn parse_text<T, F: FnOnce(String) -> crate::Result<T>>(&mut self, f: F) -> crate::Result<T> {
+        self.read_inner_value(|this| f(this.read_text()?))
+    }
+
+    fn read_text(&mut self) -> crate::Result<String> {
+        self.read_str().map(Cow::into_owned)
+    }
+
+    /// Read text as `read_text` does, borrowing it from the input if it is there unchanged
+    fn read_str(&mut self) -> crate::Result<Cow<'de, str>> {
+        if let xml::reader::XmlEvent::EndElement { .. } = self.peek()? {
+            return Ok(Cow::Borrowed(""));
+        }
+
+        match self.next()? {
+            xml::reader::XmlEvent::CData(mut s) | xml::reader::XmlEvent::Characters(mut s) => {
+                let position = self.position;
+                let mut joined = false;
+                // Text may come in several runs, split by CDATA sections or comments
+                loop {
+                    match self.peek()? {
+                        xml::reader::XmlEvent::CData(_) | xml::reader::XmlEvent::Characters(_) => {
+                            self.reset_peek();
+                            if let xml::reader::XmlEvent::CData(more)
+                            | xml::reader::XmlEvent::Characters(more) = self.next()?
+                            {
+                                s.push_str(&more);
+                                joined = true;
+                            }
                         }
-                        if let Some(e) = event.as_writer_event() {
-                            trace!("{:?}; {}; {}", event, this.depth, depth);
-                            writer.write(e).unwrap();
+                        _ => {
+                            self.reset_peek();
+                            break;
                         }
                     }
-                    writer
-                        .write(xml::writer::XmlEvent::EndElement {
-                            name: Some(name.borrow()),
-                        })
-                        .unwrap();
-                    Ok(String::from_utf8(output).unwrap())
                 }
-                _ => Err(crate::Error::ExpectedString),
+                let borrowed = match (&mut self.input, position, joined) {
+                    (Some(input), Some(position), false) => input.text(position, &s),
+                    _ => None,
+                };
+                Ok(borrowed.map_or(Cow::Owned(s), Cow::Borrowed))
             }
-        })
+            xml::reader::XmlEvent::StartElement {
+                name,
+                attributes,
+                namespace,
+            } => self
+                .write_element(name, attributes, namespace, false)
+                .map(Cow::Owned),
+            _ => Err(crate::Error::ExpectedString),
+        }
+    }
+
+    /// Read the next element as markup, for `RawXml`
+    fn read_raw(&mut self) -> crate::Result<String> {
+        self.unset_map_value();
+        match self.next()? {
+            xml::reader::XmlEvent::StartElement {
+                name,
+                attributes,
+                namespace,
+            } => self.write_element(name, attributes, namespace, true),
+            _ => Err(crate::Error::ExpectedElement),
+        }
+    }
+
+    /// Write the element just started and everything in it back out as markup
+    ///
+    /// `verbatim` keeps the whitespace the reader trimmed and every comment, as `RawXml` does;
+    /// otherwise comments are kept unless `Options::ignore_comments` is set. Processing
+    /// instructions are always kept.
+    fn write_element(
+        &mut self,
+        name: xml::name::OwnedName,
+        attributes: Vec<xml::attribute::OwnedAttribute>,
+        namespace: xml::namespace::Namespace,
+        verbatim: bool,
+    ) -> crate::Result<String> {
+        let mut output: Vec<u8> = Vec::new();
+        let conf = xml::writer::EmitterConfig::new()
+            .perform_indent(false)
+            .write_document_declaration(false)
+            .normalize_empty_elements(true)
+            .cdata_to_characters(false)
+            .keep_element_names_stack(false)
+            .pad_self_closing(false);
+        let mut writer = conf.create_writer(&mut output);
+        writer.write(xml::writer::XmlEvent::StartElement {
+            name: name.borrow(),
+            attributes: attributes.iter().map(|a| a.borrow()).collect(),
+            namespace: std::borrow::Cow::Borrowed(&namespace),
+        })?;
+        let depth = self.depth - 1;
+        loop {
+            let event = self.next()?;
+            trace!("{:?}; {}; {}", event, self.depth, depth);
+            for skipped in &self.skipped {
+                match skipped {
+                    Skipped::Comment(comment) if verbatim || !self.options.ignore_comments => {
+                        writer.write(xml::writer::XmlEvent::Comment(comment))?
+                    }
+                    Skipped::Whitespace(space) if verbatim => {
+                        writer.write(xml::writer::XmlEvent::Characters(space))?
+                    }
+                    Skipped::ProcessingInstruction { name, data } => {
+                        writer.write(xml::writer::XmlEvent::ProcessingInstruction {
+                            name,
+                            data: data.as_deref(),
+                        })?
+                    }
+                    _ => {}
+                }
+            }
+            if self.depth == depth {
+                break;
+            }
+            if let Some(e) = event.as_writer_event() {
+                trace!("{:?}; {}; {}", event, self.depth, depth);
+                writer.write(e)?;
+            }
+        }
+        writer.write(xml::writer::XmlEvent::EndElement {
+            name: Some(name.borrow()),
+        })?;
+        Ok(String::from_utf8(output).unwrap())
     }
 
     fn parse_bool(&mut self) -> crate::Result<bool> {
-        let s = self.parse_string()?;
-        match s.to_lowercase().as_str() {
+        self.parse_text(|s| match s.to_lowercase().as_str() {
             "true" | "1" | "y" => Ok(true),
             "false" | "0" | "n" => Ok(false),
-            _ => Err(crate::Error::ExpectedBool),
-        }
+            _ => Err(crate::Error::ExpectedBool { found: s }),
+        })
     }
 
     fn parse_int<T: AddAssign<T> + MulAssign<T> + std::str::FromStr>(
         &mut self,
     ) -> crate::Result<T> {
-        let s = self.parse_string()?;
-        match s.parse::<T>() {
+        self.parse_text(|s| match s.parse::<T>() {
             Ok(i) => Ok(i),
-            Err(_) => Err(crate::Error::ExpectedInt),
-        }
+            Err(_) => Err(crate::Error::ExpectedInt { found: s }),
+        })
+    }
+
+    fn parse_float<T: std::str::FromStr>(&mut self) -> crate::Result<T> {
+        self.parse_text(|s| parse_xsd_float(&s).ok_or(crate::Error::ExpectedInt { found: s }))
+    }
+}
+
+/// Convert an error from the parser, picking out the limits enforced while reading
+fn reader_error(err: &xml::reader::Error) -> crate::Error {
+    let limit = match err.kind() {
+        xml::reader::ErrorKind::Io(io) => io.get_ref().and_then(|e| e.downcast_ref::<LimitError>()),
+        _ => None,
+    };
+    match limit {
+        Some(LimitError { limit, max }) => crate::Error::LimitExceeded {
+            limit,
+            max: *max,
+        },
+        None => err.into(),
     }
 }
 
-impl<'de, 'a, I: Iterator<Item = XmlRes>> de::Deserializer<'de> for &'a mut Deserializer<I> {
+/// Parse a number in the lexical space of XSD `float` and `double`
+///
+/// This accepts exponents, a leading `+`, surrounding whitespace, and the special values `INF`,
+/// `+INF`, `-INF` and `NaN`, but not Rust's own spellings such as `inf` or `infinity`.
+fn parse_xsd_float<T: std::str::FromStr>(s: &str) -> Option<T> {
+    let s = s.trim();
+    let s = match s {
+        "INF" | "+INF" => "inf",
+        "-INF" => "-inf",
+        "NaN" => "NaN",
+        _ if s.contains(|c: char| c.is_alphabetic() && c != 'e' && c != 'E') => return None,
+        _ => s,
+    };
+    s.parse().ok()
+}
+
+impl<'de, I: Iterator<Item = XmlRes>> de::Deserializer<'de> for &mut Deserializer<'de, I> {
     type Error = crate::Error;
 
-    fn deserialize_any<V: serde::de::Visitor<'de>>(mut self, visitor: V) -> crate::Result<V::Value> {
+    fn deserialize_any<V: serde::de::Visitor<'de>>(self, visitor: V) -> crate::Result<V::Value> {
         trace!("deserialize_any()");
         if self.is_map_value && !self.unset_seq_value() {
             self.reset_peek();
@@ -391,7 +1292,7 @@ impl<'de, 'a, I: Iterator<Item = XmlRes>> de::Deserializer<'de> for &'a mut Dese
                         self.reset_peek_offset = 0;
                         self.reset_peek();
                         self.set_map_value();
-                        return visitor.visit_seq(Seq::new(&mut self)?);
+                        return visitor.visit_seq(Seq::new(self)?);
                     }
                 }
             }
@@ -420,8 +1321,6 @@ impl<'de, 'a, I: Iterator<Item = XmlRes>> de::Deserializer<'de> for &'a mut Dese
                 }
             }
         })
-        // self.peek();
-        // Err(crate::Error::Unsupported)
     }
 
     fn deserialize_bool<V: serde::de::Visitor<'de>>(self, visitor: V) -> crate::Result<V::Value> {
@@ -461,11 +1360,11 @@ impl<'de, 'a, I: Iterator<Item = XmlRes>> de::Deserializer<'de> for &'a mut Dese
     }
 
     fn deserialize_f32<V: serde::de::Visitor<'de>>(self, visitor: V) -> crate::Result<V::Value> {
-        visitor.visit_u64(self.parse_int()?)
+        visitor.visit_f32(self.parse_float()?)
     }
 
     fn deserialize_f64<V: serde::de::Visitor<'de>>(self, visitor: V) -> crate::Result<V::Value> {
-        visitor.visit_u64(self.parse_int()?)
+        visitor.visit_f64(self.parse_float()?)
     }
 
     fn deserialize_char<V: serde::de::Visitor<'de>>(self, visitor: V) -> crate::Result<V::Value> {
@@ -475,38 +1374,57 @@ impl<'de, 'a, I: Iterator<Item = XmlRes>> de::Deserializer<'de> for &'a mut Dese
         if s.len() == 1 {
             visitor.visit_char(match char::from_str(&s) {
                 Ok(c) => c,
-                Err(_) => return Err(crate::Error::ExpectedChar),
+                Err(_) => return Err(crate::Error::ExpectedChar { found: s }),
             })
         } else {
-            Err(crate::Error::ExpectedChar)
+            Err(crate::Error::ExpectedChar { found: s })
         }
     }
 
     fn deserialize_str<V: serde::de::Visitor<'de>>(self, visitor: V) -> crate::Result<V::Value> {
-        visitor.visit_string(self.parse_string()?)
+        self.read_inner_value(|this| match this.read_str()? {
+            Cow::Borrowed(s) => visitor.visit_borrowed_str(s),
+            Cow::Owned(s) => visitor.visit_string(s),
+        })
     }
 
     fn deserialize_string<V: serde::de::Visitor<'de>>(self, visitor: V) -> crate::Result<V::Value> {
-        self.deserialize_str(visitor)
+        visitor.visit_string(self.parse_string()?)
     }
 
-    fn deserialize_bytes<V: serde::de::Visitor<'de>>(self, _visitor: V) -> crate::Result<V::Value> {
-        trace!("deserialize_bytes()");
-        Err(crate::Error::Unsupported)
+    fn deserialize_bytes<V: serde::de::Visitor<'de>>(self, visitor: V) -> crate::Result<V::Value> {
+        self.deserialize_byte_buf(visitor)
     }
 
     fn deserialize_byte_buf<V: serde::de::Visitor<'de>>(
         self,
-        _visitor: V,
+        visitor: V,
     ) -> crate::Result<V::Value> {
-        trace!("deserialize_byte_buf()");
-        Err(crate::Error::Unsupported)
+        trace!("deserialize_byte_buf({:?})", self.binary);
+        let binary = self.binary;
+        let v = self.parse_text(|s| {
+            binary
+                .decode(&s)
+                .ok_or(crate::Error::ExpectedBinary { found: s })
+        })?;
+        visitor.visit_byte_buf(v)
     }
 
     fn deserialize_option<V: serde::de::Visitor<'de>>(self, visitor: V) -> crate::Result<V::Value> {
         trace!("deserialize_option()");
         if self.is_map_value {
             if let xml::reader::XmlEvent::StartElement { attributes, .. } = self.peek()? {
+                let nil = attributes.iter().any(|attr| {
+                    attr.name.namespace.as_deref() == Some(XSI_NS)
+                        && attr.name.local_name == "nil"
+                        && matches!(attr.value.trim(), "true" | "1")
+                });
+                if nil {
+                    self.reset_peek();
+                    self.unset_map_value();
+                    self.step_over()?;
+                    return visitor.visit_none();
+                }
                 if !attributes.is_empty() {
                     self.reset_peek();
                     return visitor.visit_some(self);
@@ -544,12 +1462,15 @@ impl<'de, 'a, I: Iterator<Item = XmlRes>> de::Deserializer<'de> for &'a mut Dese
         visitor: V,
     ) -> crate::Result<V::Value> {
         trace!("deserialize_newtype_struct({:?})", name);
+        if name == crate::raw::RAW_XML {
+            return visitor.visit_string(self.read_raw()?);
+        }
         visitor.visit_newtype_struct(self)
     }
 
-    fn deserialize_seq<V: serde::de::Visitor<'de>>(mut self, visitor: V) -> crate::Result<V::Value> {
+    fn deserialize_seq<V: serde::de::Visitor<'de>>(self, visitor: V) -> crate::Result<V::Value> {
         trace!("deserialize_seq()");
-        let res = visitor.visit_seq(Seq::new(&mut self)?)?;
+        let res = visitor.visit_seq(Seq::new(self)?)?;
         self.unset_seq_value();
         Ok(res)
     }
@@ -595,6 +1516,20 @@ impl<'de, 'a, I: Iterator<Item = XmlRes>> de::Deserializer<'de> for &'a mut Dese
         visitor: V,
     ) -> crate::Result<V::Value> {
         trace!("deserialize_enum({:?}, {:?})", name, variants);
+        if variants.iter().any(|v| v.starts_with("$type:")) {
+            self.unset_is_value();
+            let variant = match self.peek()? {
+                xml::reader::XmlEvent::StartElement {
+                    attributes,
+                    namespace,
+                    ..
+                } => match_xsi_type(variants, attributes, namespace),
+                _ => Err(crate::Error::ExpectedElement),
+            };
+            self.reset_peek();
+            let variant = variant?;
+            return visitor.visit_enum(TypedEnum { de: self, variant });
+        }
         if self.unset_is_value() {
             visitor.visit_enum(Enum::new(self, variants))
         } else {
@@ -620,13 +1555,55 @@ impl<'de, 'a, I: Iterator<Item = XmlRes>> de::Deserializer<'de> for &'a mut Dese
     }
 }
 
-struct Seq<'a, I: Iterator<Item = XmlRes>> {
-    de: &'a mut Deserializer<I>,
+/// Child elements decoded one at a time, as returned by `Deserializer::children_at`
+///
+/// Iteration stops at the end of the parent element, leaving the deserializer just after it, or
+/// after the first error.
+pub struct Children<'a, 'de, I: Iterator<Item = XmlRes>, T> {
+    de: &'a mut Deserializer<'de, I>,
+    done: bool,
+    item: std::marker::PhantomData<fn() -> T>,
+}
+
+impl<'a, 'de, I: Iterator<Item = XmlRes>, T: de::DeserializeOwned> Iterator
+    for Children<'a, 'de, I, T>
+{
+    type Item = crate::Result<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let err = match self.de.next_element() {
+            Ok(Some(item)) => return Some(Ok(item)),
+            Ok(None) => {
+                self.done = true;
+                let is_end = matches!(self.de.peek(), Ok(xml::reader::XmlEvent::EndElement { .. }));
+                self.de.reset_peek();
+                match is_end {
+                    true => match self.de.next() {
+                        Ok(_) => return None,
+                        Err(err) => self.de.locate(err),
+                    },
+                    false => return None,
+                }
+            }
+            Err(err) => err,
+        };
+        self.done = true;
+        Some(Err(err))
+    }
+}
+
+struct Seq<'a, 'de, I: Iterator<Item = XmlRes>> {
+    de: &'a mut Deserializer<'de, I>,
     expected_name: Option<xml::name::OwnedName>,
+    /// Siblings read past to get at more items, with `Options::interleaved_sequences`
+    skipped: Vec<(xml::reader::XmlEvent, Option<xml::common::TextPosition>)>,
 }
 
-impl<'a, I: Iterator<Item = XmlRes>> Seq<'a, I> {
-    fn new(de: &'a mut Deserializer<I>) -> crate::Result<Self> {
+impl<'a, 'de, I: Iterator<Item = XmlRes>> Seq<'a, 'de, I> {
+    fn new(de: &'a mut Deserializer<'de, I>) -> crate::Result<Self> {
         trace!("Seq::new()");
         let name = if de.unset_map_value() {
             let val = match de.peek()? {
@@ -641,11 +1618,53 @@ impl<'a, I: Iterator<Item = XmlRes>> Seq<'a, I> {
         Ok(Self {
             de,
             expected_name: name,
+            skipped: vec![],
         })
     }
+
+    /// Look past the siblings in the way for another item, moving them aside if there is one
+    fn skip_to_next(&mut self) -> crate::Result<bool> {
+        let expected_name = match &self.expected_name {
+            Some(name) if self.de.options.interleaved_sequences && self.de.is_greedy => name,
+            _ => return Ok(false),
+        };
+        let mut depth = 0;
+        let mut skip = 0;
+        let found = loop {
+            match self.de.peek()? {
+                xml::reader::XmlEvent::StartElement { name, .. } => {
+                    if depth == 0 && name == expected_name {
+                        break true;
+                    }
+                    depth += 1;
+                }
+                xml::reader::XmlEvent::EndElement { .. } if depth == 0 => break false,
+                xml::reader::XmlEvent::EndElement { .. } => depth -= 1,
+                xml::reader::XmlEvent::EndDocument => break false,
+                _ => {}
+            }
+            skip += 1;
+        };
+        self.de.reset_peek();
+        if found {
+            for _ in 0..skip {
+                let event = self.de.next()?;
+                self.skipped.push((event, self.de.position));
+            }
+        }
+        Ok(found)
+    }
 }
 
-impl<'de, 'a, I: Iterator<Item = XmlRes>> de::SeqAccess<'de> for Seq<'a, I> {
+impl<'a, 'de, I: Iterator<Item = XmlRes>> Drop for Seq<'a, 'de, I> {
+    fn drop(&mut self) {
+        if !self.skipped.is_empty() {
+            self.de.unread(std::mem::take(&mut self.skipped));
+        }
+    }
+}
+
+impl<'de, 'a, I: Iterator<Item = XmlRes>> de::SeqAccess<'de> for Seq<'a, 'de, I> {
     type Error = crate::Error;
 
     fn next_element_seed<T: de::DeserializeSeed<'de>>(
@@ -659,11 +1678,11 @@ impl<'de, 'a, I: Iterator<Item = XmlRes>> de::SeqAccess<'de> for Seq<'a, I> {
             }
             (xml::reader::XmlEvent::EndElement { .. }, None)
             | (_, Some(_))
-            | (xml::reader::XmlEvent::EndDocument { .. }, _) => false,
+            | (xml::reader::XmlEvent::EndDocument, _) => false,
             (_, None) => true,
         };
         self.de.reset_peek();
-        if more {
+        if more || self.skip_to_next()? {
             if self.expected_name.is_some() {
                 self.de.set_map_value();
             }
@@ -675,18 +1694,173 @@ impl<'de, 'a, I: Iterator<Item = XmlRes>> de::SeqAccess<'de> for Seq<'a, I> {
     }
 }
 
+/// Sequence of the child nodes of an element for a `$mixed` field, in document order
+///
+/// Text runs are given to the item enum as a `$text` variant, and child elements as the variant
+/// named by their tag.
+///
+/// The sequence ends before an element the struct has a field of its own for.
+struct MixedSeq<'a, 'de, I: Iterator<Item = XmlRes>> {
+    de: &'a mut Deserializer<'de, I>,
+    fields: &'a Fields,
+    /// Whether the whitespace before the next event has already gone into a text run
+    space_taken: bool,
+}
+
+impl<'de, 'a, I: Iterator<Item = XmlRes>> de::SeqAccess<'de> for MixedSeq<'a, 'de, I> {
+    type Error = crate::Error;
+
+    fn next_element_seed<T: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> crate::Result<Option<T::Value>> {
+        trace!("MixedSeq::next_element_seed()");
+        let next = match self.de.peek()? {
+            xml::reader::XmlEvent::Characters(_) | xml::reader::XmlEvent::CData(_) => Some(true),
+            xml::reader::XmlEvent::StartElement { name, .. } if !self.fields.declares(name) => {
+                Some(false)
+            }
+            _ => None,
+        };
+        self.de.reset_peek();
+        // Whitespace the reader trimmed is part of the text in mixed content
+        let mut text = match std::mem::replace(&mut self.space_taken, false) {
+            true => String::new(),
+            false => self.de.peek_whitespace(),
+        };
+        match next {
+            Some(true) => {}
+            _ if !text.is_empty() => {
+                self.space_taken = true;
+                return seed.deserialize(MixedText(text)).map(Some);
+            }
+            Some(false) => {
+                self.de.set_is_value();
+                return seed.deserialize(&mut *self.de).map(Some);
+            }
+            None => return Ok(None),
+        }
+        // Text may come in several runs, split by CDATA sections or comments
+        loop {
+            if let xml::reader::XmlEvent::CData(run) | xml::reader::XmlEvent::Characters(run) =
+                self.de.next()?
+            {
+                text.push_str(&run);
+            }
+            let more = matches!(
+                self.de.peek()?,
+                xml::reader::XmlEvent::CData(_) | xml::reader::XmlEvent::Characters(_)
+            );
+            self.de.reset_peek();
+            text.push_str(&self.de.peek_whitespace());
+            if !more {
+                break;
+            }
+        }
+        self.space_taken = true;
+        seed.deserialize(MixedText(text)).map(Some)
+    }
+}
+
+impl<'de, 'a, I: Iterator<Item = XmlRes>> de::Deserializer<'de> for MixedSeq<'a, 'de, I> {
+    type Error = crate::Error;
+
+    fn deserialize_any<V: de::Visitor<'de>>(self, visitor: V) -> crate::Result<V::Value> {
+        visitor.visit_seq(self)
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct enum identifier ignored_any
+    }
+}
+
+/// A text run in mixed content, as the `$text` variant of the item enum
+struct MixedText(String);
+
+impl<'de> de::Deserializer<'de> for MixedText {
+    type Error = crate::Error;
+
+    fn deserialize_any<V: de::Visitor<'de>>(self, visitor: V) -> crate::Result<V::Value> {
+        visitor.visit_string(self.0)
+    }
+
+    fn deserialize_enum<V: de::Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> crate::Result<V::Value> {
+        visitor.visit_enum(self)
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct identifier ignored_any
+    }
+}
+
+impl<'de> de::EnumAccess<'de> for MixedText {
+    type Error = crate::Error;
+    type Variant = Self;
+
+    fn variant_seed<V: de::DeserializeSeed<'de>>(
+        self,
+        seed: V,
+    ) -> crate::Result<(V::Value, Self::Variant)> {
+        let variant: serde::de::value::StrDeserializer<crate::Error> = "$text".into_deserializer();
+        Ok((seed.deserialize(variant)?, self))
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for MixedText {
+    type Error = crate::Error;
+
+    fn unit_variant(self) -> crate::Result<()> {
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T: de::DeserializeSeed<'de>>(self, seed: T) -> crate::Result<T::Value> {
+        seed.deserialize(AttrValueDeserializer(Cow::Owned(self.0), BinaryEncoding::default()))
+    }
+
+    fn tuple_variant<V: de::Visitor<'de>>(
+        self,
+        _len: usize,
+        _visitor: V,
+    ) -> crate::Result<V::Value> {
+        Err(crate::Error::Unsupported {
+            method: "tuple_variant",
+        })
+    }
+
+    fn struct_variant<V: de::Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        _visitor: V,
+    ) -> crate::Result<V::Value> {
+        Err(crate::Error::Unsupported {
+            method: "struct_variant",
+        })
+    }
+}
+
 struct Fields {
     fields: &'static [Field],
     inner_value: bool,
     num_value: u64,
     value_used: u64,
+    /// Whether there is a `$mixed` field taking all child nodes
+    mixed: bool,
 }
 
 #[derive(Clone)]
 struct Field {
     namespace: Option<&'static str>,
     local_name: &'static str,
-    name: &'static str,
+    key: &'static str,
     attr: bool,
 }
 
@@ -694,23 +1868,30 @@ impl From<&&'static str> for Field {
     fn from(from: &&'static str) -> Self {
         let mut attr = false;
 
-        let name = if let Some(stripped) = from.strip_prefix("$attr:") {
+        let (_, name) = crate::BinaryEncoding::split_tag(from);
+        let name = if let Some(stripped) = name.strip_prefix("$attr:") {
             attr = true;
             stripped
         } else {
-            from
+            name
         };
 
         let Tag {
             e: local_name,
             n: namespace,
+            p: prefix,
             ..
         } = crate::Tag::from_static(name);
+        // The `xml` prefix is bound to its namespace without having to be declared
+        let namespace = match prefix {
+            Some("xml") => namespace.or(Some(XML_NS)),
+            _ => namespace,
+        };
 
         Field {
             namespace,
             local_name,
-            name,
+            key: from,
             attr,
         }
     }
@@ -724,8 +1905,8 @@ impl From<&'static [&'static str]> for Fields {
 
         let (fields, num_value) = {
             // Make a single global BTreeMap to act as a cache
-            static CACHE: OnceCell<Mutex<BTreeMap<usize, (&'static [Field], u64)>>> =
-                OnceCell::new();
+            type CacheEntry = (&'static [Field], u64);
+            static CACHE: OnceCell<Mutex<BTreeMap<usize, CacheEntry>>> = OnceCell::new();
             let mut cache = CACHE
                 .get_or_init(|| Mutex::new(BTreeMap::new()))
                 .lock()
@@ -742,7 +1923,7 @@ impl From<&'static [&'static str]> for Fields {
                     let fields = Box::leak(fields.into_boxed_slice());
 
                     // Count how many $value fields we have
-                    let num_value = from.iter().filter(|f| f.starts_with(&"$value")).count() as u64;
+                    let num_value = from.iter().filter(|f| f.starts_with("$value")).count() as u64;
 
                     // Add it to the cache
                     *e.insert((fields, num_value))
@@ -760,6 +1941,7 @@ impl From<&'static [&'static str]> for Fields {
             inner_value: num_value >= 1,
             num_value,
             value_used: 0,
+            mixed: fields.iter().any(|field| field.key == "$mixed"),
         }
     }
 }
@@ -771,8 +1953,8 @@ impl Fields {
                 && field.namespace == name.namespace.as_deref()
                 && !field.attr
             {
-                trace!("match_field({:?}) -> {:?}", name, field.name);
-                return field.name.into();
+                trace!("match_field({:?}) -> {:?}", name, field.key);
+                return field.key.into();
             }
         }
 
@@ -799,9 +1981,8 @@ impl Fields {
                 && field.namespace == name.namespace.as_deref()
                 && field.attr
             {
-                let name_str = format!("$attr:{}", field.name);
-                trace!("match_attr({:?}) -> {:?}", name, name_str);
-                return name_str.into();
+                trace!("match_attr({:?}) -> {:?}", name, field.key);
+                return field.key.into();
             }
         }
 
@@ -814,32 +1995,95 @@ impl Fields {
         trace!("match_attr({:?}) -> {:?}", name, name_str);
         name_str.into()
     }
+
+    /// Whether the struct has a field of its own for a child element
+    fn declares(&self, name: &xml::name::OwnedName) -> bool {
+        self.fields.iter().any(|field| {
+            field.local_name == name.local_name
+                && field.namespace == name.namespace.as_deref()
+                && !field.attr
+        })
+    }
+
+    /// Whether a name returned by `match_field` or `match_attr` belongs to the struct, rather
+    /// than being made up for an element or attribute it has no field for
+    ///
+    /// Maps are deserialized without any fields, so everything in them counts as known.
+    fn is_known(&self, key: &str) -> bool {
+        self.fields.is_empty()
+            || key.starts_with("$value")
+            || self.fields.iter().any(|field| field.key == key)
+    }
 }
 
-struct Map<'a, I: Iterator<Item = XmlRes>> {
-    de: &'a mut Deserializer<I>,
+struct Map<'a, 'de, I: Iterator<Item = XmlRes>> {
+    de: &'a mut Deserializer<'de, I>,
     attrs: Vec<xml::attribute::OwnedAttribute>,
     fields: Fields,
-    next_value: Option<String>,
+    next_value: Option<Cow<'de, str>>,
+    next_binary: BinaryEncoding,
     inner_value: bool,
     next_is_value: bool,
+    next_is_mixed: bool,
+    /// Whether the `$mixed` field has been given, so it is not given empty at the end as well
+    mixed_given: bool,
 }
 
-impl<'a, I: Iterator<Item=XmlRes>> Map<'a, I> {
-    fn new(de: &'a mut Deserializer<I>, attrs: Vec<xml::attribute::OwnedAttribute>, fields: &'static [&'static str]) -> Self {
+impl<'a, 'de, I: Iterator<Item = XmlRes>> Map<'a, 'de, I> {
+    fn new(de: &'a mut Deserializer<'de, I>, attrs: Vec<xml::attribute::OwnedAttribute>, fields: &'static [&'static str]) -> Self {
         trace!("Map::new({:?})", fields);
         Self {
             de,
             attrs,
             fields: fields.into(),
             next_value: None,
+            next_binary: BinaryEncoding::default(),
             inner_value: true,
             next_is_value: false,
+            next_is_mixed: false,
+            mixed_given: false,
         }
     }
+
+    /// The key for the child node to be read next, if there is one
+    fn next_child_key<K: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> crate::Result<Option<K::Value>> {
+        let mut unknown = None;
+        let val = match *self.de.peek()? {
+            xml::reader::XmlEvent::StartElement { ref name, .. } => {
+                let key = self.fields.match_field(name);
+                if !self.fields.is_known(&key) {
+                    unknown = Some(Box::new(name.clone()));
+                }
+                let name = key;
+                self.next_binary = BinaryEncoding::split_tag(&name)
+                    .0
+                    .unwrap_or(self.de.options.binary_encoding);
+                self.inner_value = name.starts_with("$value");
+                self.next_is_value = name.starts_with("$value");
+                seed.deserialize(name.as_ref().into_deserializer())
+                    .map(Some)
+            }
+            xml::reader::XmlEvent::Characters(_) | xml::reader::XmlEvent::CData(_) => {
+                self.next_is_value = true;
+                self.next_binary = self.de.options.binary_encoding;
+                seed.deserialize("$value".into_deserializer()).map(Some)
+            }
+            _ => Ok(None),
+        };
+        if let Some(name) = unknown {
+            let position = self.de.peek_position();
+            self.de
+                .unknown(crate::Error::UnknownElement { name }, position)?;
+        }
+        self.de.reset_peek();
+        val
+    }
 }
 
-impl<'de, 'a, I: Iterator<Item = XmlRes>> de::MapAccess<'de> for Map<'a, I> {
+impl<'de, 'a, I: Iterator<Item = XmlRes>> de::MapAccess<'de> for Map<'a, 'de, I> {
     type Error = crate::Error;
 
     fn next_key_seed<K: de::DeserializeSeed<'de>>(
@@ -849,30 +2093,54 @@ impl<'de, 'a, I: Iterator<Item = XmlRes>> de::MapAccess<'de> for Map<'a, I> {
         trace!("next_key_seed(); attrs = {:?}", self.attrs);
         match self.attrs.pop() {
             Some(xml::attribute::OwnedAttribute { name, value }) => {
-                let name = self.fields.match_attr(&name);
-                self.next_value = Some(value);
+                let key = self.fields.match_attr(&name);
+                if !self.fields.is_known(&key) && name.namespace.as_deref() != Some(XSI_NS) {
+                    let position = self.de.position;
+                    let name = Box::new(name);
+                    self.de
+                        .unknown(crate::Error::UnknownAttribute { name }, position)?;
+                }
+                let name = key;
+                self.next_binary = BinaryEncoding::split_tag(&name)
+                    .0
+                    .unwrap_or(self.de.options.binary_encoding);
+                let borrowed = match (&mut self.de.input, self.de.position) {
+                    (Some(input), Some(position)) => input.attribute(position, &value),
+                    _ => None,
+                };
+                self.next_value = Some(borrowed.map_or(Cow::Owned(value), Cow::Borrowed));
                 self.next_is_value = false;
                 seed.deserialize(name.as_ref().into_deserializer())
                     .map(Some)
             }
-            None => {
-                let val = match *self.de.peek()? {
-                    xml::reader::XmlEvent::StartElement { ref name, .. } => {
-                        let name = self.fields.match_field(name);
-                        self.inner_value = name.starts_with(&"$value");
-                        self.next_is_value = name.starts_with(&"$value");
-                        seed.deserialize(name.as_ref().into_deserializer())
-                            .map(Some)
-                    }
-                    xml::reader::XmlEvent::Characters(_) | xml::reader::XmlEvent::CData(_) => {
-                        self.next_is_value = true;
-                        seed.deserialize("$value".into_deserializer()).map(Some)
+            None if self.fields.mixed => {
+                let next = match self.de.peek()? {
+                    xml::reader::XmlEvent::StartElement { name, .. }
+                        if self.fields.declares(name) =>
+                    {
+                        None
                     }
-                    _ => Ok(None),
+                    xml::reader::XmlEvent::StartElement { .. }
+                    | xml::reader::XmlEvent::Characters(_)
+                    | xml::reader::XmlEvent::CData(_) => Some(true),
+                    _ => Some(false),
                 };
                 self.de.reset_peek();
-                val
+                match next {
+                    // Elements with fields of their own are matched to them, not put in `$mixed`
+                    None => self.next_child_key(seed),
+                    // The first other child node starts the `$mixed` field, which then takes the
+                    // rest up to the next element with a field, and an empty element gives it
+                    // no items
+                    Some(more) if more || !self.mixed_given => {
+                        self.mixed_given = true;
+                        self.next_is_mixed = true;
+                        seed.deserialize("$mixed".into_deserializer()).map(Some)
+                    }
+                    Some(_) => Ok(None),
+                }
             }
+            None => self.next_child_key(seed),
         }
     }
 
@@ -883,7 +2151,14 @@ impl<'de, 'a, I: Iterator<Item = XmlRes>> de::MapAccess<'de> for Map<'a, I> {
             self.next_is_value
         );
         match self.next_value.take() {
-            Some(val) => seed.deserialize(AttrValueDeserializer(val)),
+            Some(val) => seed.deserialize(AttrValueDeserializer(val, self.next_binary)),
+            None if std::mem::replace(&mut self.next_is_mixed, false) => {
+                seed.deserialize(MixedSeq {
+                    de: &mut *self.de,
+                    fields: &self.fields,
+                    space_taken: false,
+                })
+            }
             None => {
                 if !std::mem::replace(&mut self.inner_value, false) {
                     self.de.set_map_value();
@@ -895,7 +2170,10 @@ impl<'de, 'a, I: Iterator<Item = XmlRes>> de::MapAccess<'de> for Map<'a, I> {
                 if greedy {
                     self.de.set_not_greedy();
                 }
-                let val = seed.deserialize(&mut *self.de)?;
+                let binary = std::mem::replace(&mut self.de.binary, self.next_binary);
+                let val = seed.deserialize(&mut *self.de);
+                self.de.binary = binary;
+                let val = val?;
                 if greedy {
                     self.de.unset_not_greedy();
                     self.de.reset_peek();
@@ -906,13 +2184,13 @@ impl<'de, 'a, I: Iterator<Item = XmlRes>> de::MapAccess<'de> for Map<'a, I> {
     }
 }
 
-pub struct Enum<'a, I: Iterator<Item = XmlRes>> {
-    de: &'a mut Deserializer<I>,
+pub struct Enum<'a, 'de, I: Iterator<Item = XmlRes>> {
+    de: &'a mut Deserializer<'de, I>,
     fields: Fields,
 }
 
-impl<'a, I: Iterator<Item = XmlRes>> Enum<'a, I> {
-    pub fn new(de: &'a mut Deserializer<I>, fields: &'static [&'static str]) -> Self {
+impl<'a, 'de, I: Iterator<Item = XmlRes>> Enum<'a, 'de, I> {
+    pub fn new(de: &'a mut Deserializer<'de, I>, fields: &'static [&'static str]) -> Self {
         trace!("Enum::new({:?})", fields);
         Self {
             de,
@@ -921,7 +2199,7 @@ impl<'a, I: Iterator<Item = XmlRes>> Enum<'a, I> {
     }
 }
 
-impl<'de, 'a, I: Iterator<Item = XmlRes>> de::EnumAccess<'de> for Enum<'a, I> {
+impl<'de, 'a, I: Iterator<Item = XmlRes>> de::EnumAccess<'de> for Enum<'a, 'de, I> {
     type Error = crate::Error;
     type Variant = Self;
 
@@ -933,7 +2211,7 @@ impl<'de, 'a, I: Iterator<Item = XmlRes>> de::EnumAccess<'de> for Enum<'a, I> {
         let val = match self.de.peek()? {
             xml::reader::XmlEvent::StartElement { name, .. } => {
                 let name_str = self.fields.match_field(name);
-                if !name_str.starts_with(&"$value") {
+                if !name_str.starts_with("$value") {
                     self.de.set_map_value();
                 }
                 let name_str: serde::de::value::CowStrDeserializer<crate::Error> =
@@ -952,7 +2230,7 @@ impl<'de, 'a, I: Iterator<Item = XmlRes>> de::EnumAccess<'de> for Enum<'a, I> {
     }
 }
 
-impl<'de, 'a, I: Iterator<Item = XmlRes>> de::VariantAccess<'de> for Enum<'a, I> {
+impl<'de, 'a, I: Iterator<Item = XmlRes>> de::VariantAccess<'de> for Enum<'a, 'de, I> {
     type Error = crate::Error;
 
     fn unit_variant(self) -> crate::Result<()> {
@@ -995,24 +2273,114 @@ impl<'de, 'a, I: Iterator<Item = XmlRes>> de::VariantAccess<'de> for Enum<'a, I>
     }
 }
 
-struct AttrValueDeserializer(String);
+/// Find the `$type:` variant named by the `xsi:type` attribute of an element, resolving its
+/// prefix against the namespaces in scope there
+fn match_xsi_type(
+    variants: &'static [&'static str],
+    attributes: &[xml::attribute::OwnedAttribute],
+    namespace: &xml::namespace::Namespace,
+) -> crate::Result<&'static str> {
+    let type_name = attributes
+        .iter()
+        .find(|attr| {
+            attr.name.namespace.as_deref() == Some(XSI_NS) && attr.name.local_name == "type"
+        })
+        .map(|attr| attr.value.trim())
+        .ok_or(crate::Error::UnknownType { found: None })?;
+    let (prefix, local_name) = type_name.split_once(':').unwrap_or(("", type_name));
+    let type_namespace = namespace.get(prefix).filter(|n| !n.is_empty());
+
+    variants
+        .iter()
+        .copied()
+        .find(|variant| match variant.strip_prefix("$type:") {
+            Some(tag) => {
+                let tag = Tag::from_static(tag);
+                tag.e == local_name && tag.n == type_namespace
+            }
+            None => false,
+        })
+        .ok_or_else(|| crate::Error::UnknownType {
+            found: Some(type_name.to_string()),
+        })
+}
+
+/// Access to an enum whose variant is given by the `xsi:type` of the element holding it, rather
+/// than by a child element
+struct TypedEnum<'a, 'de, I: Iterator<Item = XmlRes>> {
+    de: &'a mut Deserializer<'de, I>,
+    variant: &'static str,
+}
+
+impl<'de, 'a, I: Iterator<Item = XmlRes>> de::EnumAccess<'de> for TypedEnum<'a, 'de, I> {
+    type Error = crate::Error;
+    type Variant = Self;
+
+    fn variant_seed<V: de::DeserializeSeed<'de>>(
+        self,
+        seed: V,
+    ) -> crate::Result<(V::Value, Self::Variant)> {
+        trace!("variant_seed({:?})", self.variant);
+        let variant: serde::de::value::StrDeserializer<crate::Error> =
+            self.variant.into_deserializer();
+        Ok((seed.deserialize(variant)?, self))
+    }
+}
+
+impl<'de, 'a, I: Iterator<Item = XmlRes>> de::VariantAccess<'de> for TypedEnum<'a, 'de, I> {
+    type Error = crate::Error;
+
+    fn unit_variant(self) -> crate::Result<()> {
+        trace!("unit_variant()");
+        if self.de.unset_map_value() {
+            self.de.step_over()?;
+        }
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T: de::DeserializeSeed<'de>>(self, seed: T) -> crate::Result<T::Value> {
+        trace!("newtype_variant_seed()");
+        seed.deserialize(self.de)
+    }
+
+    fn tuple_variant<V: de::Visitor<'de>>(self, len: usize, visitor: V) -> crate::Result<V::Value> {
+        trace!("tuple_variant({:?})", len);
+        use serde::de::Deserializer;
+        self.de.deserialize_tuple(len, visitor)
+    }
+
+    fn struct_variant<V: de::Visitor<'de>>(
+        self,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> crate::Result<V::Value> {
+        trace!("struct_variant({:?})", fields);
+        use serde::de::Deserializer;
+        self.de.deserialize_struct("", fields, visitor)
+    }
+}
+
+struct AttrValueDeserializer<'de>(Cow<'de, str>, BinaryEncoding);
 
 macro_rules! deserialize_type_attr {
     ($deserialize:ident => $visit:ident) => {
         fn $deserialize<V: de::Visitor<'de>>(self, visitor: V) -> crate::Result<V::Value> {
             visitor.$visit(match self.0.parse() {
                 Ok(v) => v,
-                Err(_) => return Err(crate::Error::ExpectedInt),
+                Err(_) => return Err(crate::Error::ExpectedInt { found: self.0.into_owned() }),
             })
         }
     };
 }
 
-impl<'de> serde::de::Deserializer<'de> for AttrValueDeserializer {
+impl<'de> serde::de::Deserializer<'de> for AttrValueDeserializer<'de> {
     type Error = crate::Error;
 
     fn deserialize_any<V: de::Visitor<'de>>(self, visitor: V) -> crate::Result<V::Value> {
-        visitor.visit_string(self.0)
+        match self.0 {
+            Cow::Borrowed(s) => visitor.visit_borrowed_str(s),
+            Cow::Owned(s) => visitor.visit_string(s),
+        }
     }
 
     deserialize_type_attr!(deserialize_i8 => visit_i8);
@@ -1023,8 +2391,20 @@ impl<'de> serde::de::Deserializer<'de> for AttrValueDeserializer {
     deserialize_type_attr!(deserialize_u16 => visit_u16);
     deserialize_type_attr!(deserialize_u32 => visit_u32);
     deserialize_type_attr!(deserialize_u64 => visit_u64);
-    deserialize_type_attr!(deserialize_f32 => visit_f32);
-    deserialize_type_attr!(deserialize_f64 => visit_f64);
+
+    fn deserialize_f32<V: de::Visitor<'de>>(self, visitor: V) -> crate::Result<V::Value> {
+        match parse_xsd_float(&self.0) {
+            Some(v) => visitor.visit_f32(v),
+            None => Err(crate::Error::ExpectedInt { found: self.0.into_owned() }),
+        }
+    }
+
+    fn deserialize_f64<V: de::Visitor<'de>>(self, visitor: V) -> crate::Result<V::Value> {
+        match parse_xsd_float(&self.0) {
+            Some(v) => visitor.visit_f64(v),
+            None => Err(crate::Error::ExpectedInt { found: self.0.into_owned() }),
+        }
+    }
 
     fn deserialize_enum<V: de::Visitor<'de>>(
         self,
@@ -1040,17 +2420,28 @@ impl<'de> serde::de::Deserializer<'de> for AttrValueDeserializer {
         visitor.visit_some(self)
     }
 
+    fn deserialize_bytes<V: de::Visitor<'de>>(self, visitor: V) -> crate::Result<V::Value> {
+        self.deserialize_byte_buf(visitor)
+    }
+
+    fn deserialize_byte_buf<V: de::Visitor<'de>>(self, visitor: V) -> crate::Result<V::Value> {
+        match self.1.decode(&self.0) {
+            Some(v) => visitor.visit_byte_buf(v),
+            None => Err(crate::Error::ExpectedBinary { found: self.0.into_owned() }),
+        }
+    }
+
     fn deserialize_bool<V: de::Visitor<'de>>(self, visitor: V) -> crate::Result<V::Value> {
         match self.0.to_lowercase().as_str() {
             "true" | "1" | "y" => visitor.visit_bool(true),
             "false" | "0" | "n" => visitor.visit_bool(false),
-            _ => Err(crate::Error::ExpectedBool),
+            _ => Err(crate::Error::ExpectedBool { found: self.0.into_owned() }),
         }
     }
 
     serde::forward_to_deserialize_any! {
-        char str string unit seq bytes map unit_struct newtype_struct tuple_struct
-        struct identifier tuple ignored_any byte_buf
+        char str string unit seq map unit_struct newtype_struct tuple_struct
+        struct identifier tuple ignored_any
     }
 }
 
@@ -1066,8 +2457,7 @@ mod tests {
 
         assert_eq!(
             crate::from_str::<Foo>(
-                r#"
-<?xml version="1.0" encoding="utf-8" standalone="yes"?>
+                r#"<?xml version="1.0" encoding="utf-8" standalone="yes"?>
 <foo:bar xmlns:foo="urn:foo">baz</foo:bar>
             "#
             )
@@ -1092,8 +2482,7 @@ mod tests {
             baz: String,
         }
 
-        let input = r#"
-<?xml version="1.0" encoding="utf-8" standalone="yes"?>
+        let input = r#"<?xml version="1.0" encoding="utf-8" standalone="yes"?>
 <foo:bar xmlns:foo="urn:foo">
     <foo:baz xmlns:foo="urn:foo">baz</foo:baz>
 </foo:bar>"#;
@@ -1105,9 +2494,7 @@ mod tests {
 
         let events = xml::reader::EventReader::new_with_config(input.as_bytes(), parser_config)
             .into_iter()
-            .map(|event| xml::reader::Result::Ok(event.to_owned()))
-            .collect::<Result<Vec<_>, _>>()
-            .unwrap();
+            .collect::<Vec<_>>();
 
         let result = crate::from_events::<Foo>(&events).unwrap();
 
@@ -1119,6 +2506,8 @@ mod tests {
 
         assert_eq!(result, expected);
     }
+
+    #[test]
     fn deserialize_element_with_processing_instruction_into_struct() {
         #[derive(Debug, PartialEq, Deserialize)]
         struct Foo {
@@ -1128,8 +2517,7 @@ mod tests {
 
         assert_eq!(
             crate::from_str::<Foo>(
-                r#"
-<?xml version="1.0" encoding="utf-8" standalone="yes"?>
+                r#"<?xml version="1.0" encoding="utf-8" standalone="yes"?>
 <?xml-stylesheet href='foo.xsl' type='text/xsl'?>
 <foo:bar xmlns:foo="urn:foo">baz</foo:bar>
             "#
@@ -1140,4 +2528,810 @@ mod tests {
             }
         );
     }
+
+    #[test]
+    fn deserialize_xsd_floats() {
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Doc {
+            #[serde(rename = "foo")]
+            foo: Foo,
+        }
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Foo {
+            #[serde(rename = "$attr:a")]
+            a: f32,
+            #[serde(rename = "b")]
+            b: Vec<f64>,
+        }
+
+        let doc = crate::from_str::<Doc>(
+            r#"<?xml version="1.0" encoding="utf-8"?>
+<foo a="-INF">
+    <b>1.5</b>
+    <b> +1.5E3 </b>
+    <b>.5e-1</b>
+    <b>1</b>
+    <b>INF</b>
+    <b>NaN</b>
+</foo>"#,
+        )
+        .unwrap();
+        assert_eq!(doc.foo.a, f32::NEG_INFINITY);
+        assert_eq!(&doc.foo.b[..5], &[1.5, 1500.0, 0.05, 1.0, f64::INFINITY]);
+        assert!(doc.foo.b[5].is_nan());
+
+        assert!(crate::from_str::<Doc>(
+            r#"<?xml version="1.0" encoding="utf-8"?>
+<foo a="1"><b>inf</b></foo>"#
+        )
+        .is_err());
+
+        let doc = Doc {
+            foo: Foo {
+                a: 0.1,
+                b: vec![1.25, -0.0, f64::NEG_INFINITY, 1e300],
+            },
+        };
+        let xml = crate::to_string(&doc).unwrap();
+        assert!(xml.contains(r#"a="0.1""#));
+        assert!(xml.contains("<b>-INF</b>"));
+        assert_eq!(crate::from_str::<Doc>(&xml).unwrap(), doc);
+    }
+
+    #[test]
+    fn deserialize_from_reader() {
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Foo {
+            #[serde(rename = "{urn:foo}foo:bar")]
+            bar: Vec<String>,
+        }
+
+        let xml = r#"<?xml version="1.0" encoding="utf-8"?>
+<foo:bar xmlns:foo="urn:foo">a</foo:bar>
+<foo:bar xmlns:foo="urn:foo">b</foo:bar>"#;
+        let expected = Foo {
+            bar: vec!["a".to_string(), "b".to_string()],
+        };
+
+        let reader = std::io::BufReader::new(std::io::Cursor::new(xml.as_bytes().to_vec()));
+        assert_eq!(crate::from_reader::<_, Foo>(reader).unwrap(), expected);
+        assert_eq!(crate::from_slice::<Foo>(xml.as_bytes()).unwrap(), expected);
+    }
+
+    #[test]
+    fn deserialize_children() {
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Item {
+            #[serde(rename = "$attr:id")]
+            id: u32,
+            name: String,
+        }
+
+        let xml = r#"<?xml version="1.0" encoding="utf-8"?>
+<feed>
+  <title>Items</title>
+  <list>
+    <item id="1"><name>a</name></item>
+    <item id="2"><name>b</name></item>
+    <item id="x"><name>c</name></item>
+  </list>
+</feed>"#;
+
+        let mut de = crate::Deserializer::new(xml.as_bytes(), Default::default()).unwrap();
+        let mut items = de.children_at::<Item>(&["feed", "list"]).unwrap();
+        assert_eq!(
+            items.next().unwrap().unwrap(),
+            Item {
+                id: 1,
+                name: "a".to_string()
+            }
+        );
+        assert_eq!(
+            items.next().unwrap().unwrap(),
+            Item {
+                id: 2,
+                name: "b".to_string()
+            }
+        );
+        assert_eq!(
+            items.next().unwrap().unwrap_err().to_string(),
+            r#"line 7 col 5 at /feed/list/item: expected a number, found "x""#
+        );
+        assert!(items.next().is_none());
+
+        let mut de = crate::Deserializer::new(xml.as_bytes(), Default::default()).unwrap();
+        let names = de
+            .children_at::<String>(&["feed", "list", "item"])
+            .unwrap()
+            .collect::<crate::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(names, vec!["a".to_string()]);
+
+        let mut de = crate::Deserializer::new(xml.as_bytes(), Default::default()).unwrap();
+        assert!(de.children_at::<Item>(&["feed", "items"]).is_err());
+    }
+
+    #[test]
+    fn deserialize_limits() {
+        #[derive(Debug, Deserialize)]
+        struct Doc {
+            #[serde(rename = "a")]
+            _a: A,
+        }
+
+        #[derive(Debug, Deserialize)]
+        struct A {
+            #[serde(rename = "b", default)]
+            _b: Vec<String>,
+        }
+
+        fn limit(xml: &str, options: crate::DeserializerOptions) -> Option<&'static str> {
+            match crate::from_str_custom::<Doc>(xml, options) {
+                Ok(_) => None,
+                Err(err) => match err.inner() {
+                    crate::Error::LimitExceeded { limit, .. } => Some(limit),
+                    err => panic!("unexpected error {}", err),
+                },
+            }
+        }
+
+        let xml = r#"<a x="1" y="2"><b>hello</b><b><c><d/></c></b></a>"#;
+        assert_eq!(limit(xml, Default::default()), None);
+        let options = crate::DeserializerOptions {
+            max_depth: Some(3),
+            ..Default::default()
+        };
+        assert_eq!(limit(xml, options), Some("max_depth"));
+        let options = crate::DeserializerOptions {
+            max_attributes: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(limit(xml, options), Some("max_attributes"));
+        let options = crate::DeserializerOptions {
+            max_text_length: Some(4),
+            ..Default::default()
+        };
+        assert_eq!(limit(xml, options), Some("max_text_length"));
+        let options = crate::DeserializerOptions {
+            max_input_bytes: Some(xml.len() - 1),
+            ..Default::default()
+        };
+        assert_eq!(limit(xml, options), Some("max_input_bytes"));
+        let options = crate::DeserializerOptions {
+            max_input_bytes: Some(xml.len()),
+            ..Default::default()
+        };
+        assert_eq!(limit(xml, options), None);
+
+        // Whitespace and comments count as text, whether or not they are kept
+        let options = || crate::DeserializerOptions {
+            max_text_length: Some(4),
+            ..Default::default()
+        };
+        let xml = r#"<a xml:space="preserve"><b>a</b>      <b>b</b></a>"#;
+        assert_eq!(limit(xml, options()), Some("max_text_length"));
+        let xml = r#"<a><b>a</b>      <b>b</b></a>"#;
+        assert_eq!(limit(xml, options()), Some("max_text_length"));
+        let xml = r#"<a><!-- a long comment --><b>a</b></a>"#;
+        assert_eq!(limit(xml, options()), Some("max_text_length"));
+        let xml = r#"<a><b><![CDATA[a long run]]></b></a>"#;
+        assert_eq!(limit(xml, options()), Some("max_text_length"));
+
+        let xml = r#"<!DOCTYPE a [
+<!ENTITY x "xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx">
+<!ENTITY y "&x;yyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyy">
+]>
+<a><b>&y;</b></a>"#;
+        assert_eq!(limit(xml, Default::default()), None);
+        let options = crate::DeserializerOptions {
+            max_entity_expansion: Some(30),
+            ..Default::default()
+        };
+        assert_eq!(limit(xml, options), Some("max_entity_expansion"));
+
+        // Limits are checked as the parser hands events over, so they also hold when reading
+        // an element at a time
+        let xml = r#"<a><b>one</b><b>two</b><b>far too long</b></a>"#;
+        let options = crate::DeserializerOptions {
+            max_text_length: Some(5),
+            ..Default::default()
+        };
+        let mut de = crate::Deserializer::new(xml.as_bytes(), options).unwrap();
+        de.step_into(&["a"]).unwrap();
+        assert_eq!(de.next_element::<String>().unwrap(), Some("one".to_string()));
+        assert_eq!(de.next_element::<String>().unwrap(), Some("two".to_string()));
+        let err = de.next_element::<String>().unwrap_err();
+        assert!(matches!(
+            err.inner(),
+            crate::Error::LimitExceeded {
+                limit: "max_text_length",
+                max: 5
+            }
+        ));
+    }
+
+    #[test]
+    fn deserialize_parser_options() {
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Root {
+            doc: Doc,
+        }
+
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Doc {
+            a: String,
+            b: String,
+            c: String,
+            #[serde(with = "serde_bytes", default)]
+            d: Vec<u8>,
+        }
+
+        let xml = r#"<doc>
+  <a>  x <![CDATA[<y>]]> z  </a>
+  <b><!-- one -->1<!-- two -->2</b>
+  <c><e><!-- three --></e></c>
+</doc>"#;
+
+        let doc = crate::from_str::<Root>(xml).unwrap().doc;
+        assert_eq!(doc.a, "x<y>z");
+        assert_eq!(doc.b, "12");
+        assert_eq!(doc.c, "<e/>");
+
+        let options = crate::DeserializerOptions {
+            trim_whitespace: false,
+            ignore_comments: false,
+            ..Default::default()
+        };
+        let doc = crate::from_str_custom::<Root>(xml, options).unwrap().doc;
+        assert_eq!(doc.a, "  x <y> z  ");
+        assert_eq!(doc.b, "12");
+        assert_eq!(doc.c, "<e><!-- three --></e>");
+
+        let xml = "<doc><a/><b/><c/><d>AQI=</d></doc>";
+        let options = crate::DeserializerOptions {
+            binary_encoding: crate::BinaryEncoding::Base64,
+            ..Default::default()
+        };
+        let doc = crate::from_str_custom::<Root>(xml, options).unwrap().doc;
+        assert_eq!(doc.d, vec![1, 2]);
+    }
+
+    #[test]
+    fn deserialize_next_element() {
+        use std::cell::RefCell;
+        use std::collections::VecDeque;
+        use std::rc::Rc;
+
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Message {
+            #[serde(rename = "$attr:to")]
+            to: String,
+            #[serde(rename = "{jabber:client}body")]
+            body: String,
+        }
+
+        /// Input arriving in chunks, as from a socket, which must not be read past its end
+        struct Stream(Rc<RefCell<VecDeque<u8>>>);
+
+        impl std::io::Read for Stream {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                let mut input = self.0.borrow_mut();
+                assert!(!input.is_empty(), "read past the end of the input so far");
+                let len = buf.len().min(input.len());
+                for (b, byte) in buf.iter_mut().zip(input.drain(..len)) {
+                    *b = byte;
+                }
+                Ok(len)
+            }
+        }
+
+        let input = Rc::new(RefCell::new(VecDeque::new()));
+        let send = |s: &str| input.borrow_mut().extend(s.bytes());
+        send(
+            r#"<?xml version="1.0"?><stream:stream xmlns:stream="http://etherx.jabber.org/streams" xmlns="jabber:client">"#,
+        );
+        let mut de = crate::Deserializer::new(Stream(input.clone()), Default::default()).unwrap();
+        de.step_into(&["stream"]).unwrap();
+
+        send(r#"<message to="a"><body>hi</body></message>"#);
+        assert_eq!(
+            de.next_element::<Message>().unwrap(),
+            Some(Message {
+                to: "a".to_string(),
+                body: "hi".to_string()
+            })
+        );
+        send("\n<message to=\"b\">\n<body>bye</body>\n</message>");
+        let message = de.next_element::<Message>().unwrap().unwrap();
+        assert_eq!(message.body, "bye");
+        send("</stream:stream>");
+        assert_eq!(de.next_element::<Message>().unwrap(), None);
+    }
+
+    #[test]
+    fn deserialize_event_iter() {
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Doc {
+            item: Item,
+        }
+
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Item {
+            #[serde(rename = "$attr:id")]
+            id: u32,
+            name: String,
+        }
+
+        let xml = r#"<envelope><item id="1"><name>a</name></item></envelope>"#;
+        let mut reader = xml::reader::EventReader::from_str(xml).into_iter();
+        assert!(matches!(
+            reader.nth(1),
+            Some(Ok(xml::reader::XmlEvent::StartElement { name, .. })) if name.local_name == "envelope"
+        ));
+        assert_eq!(
+            crate::from_event_iter::<Doc, _>(reader).unwrap(),
+            Doc {
+                item: Item {
+                    id: 1,
+                    name: "a".to_string()
+                }
+            }
+        );
+    }
+
+    #[test]
+    fn deserialize_encodings() {
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Doc {
+            a: String,
+        }
+
+        let utf16 = "\u{FEFF}<?xml version=\"1.0\" encoding=\"UTF-16\"?><a>\u{e9}\u{20ac}</a>"
+            .encode_utf16()
+            .flat_map(u16::to_le_bytes)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            crate::from_bytes::<Doc>(&utf16).unwrap(),
+            Doc {
+                a: "\u{e9}\u{20ac}".to_string()
+            }
+        );
+
+        let latin1 = b"<?xml version='1.0' encoding='ISO-8859-1'?><a>caf\xe9 \x80</a>";
+        assert_eq!(crate::from_bytes::<Doc>(latin1).unwrap().a, "caf\u{e9} \u{80}");
+        let latin1 = b"<?xml version='1.0' encoding='l1'?><a>caf\xe9</a>";
+        assert_eq!(crate::from_bytes::<Doc>(latin1).unwrap().a, "caf\u{e9}");
+
+        let windows1252 = b"<?xml version=\"1.0\" encoding=\"windows-1252\"?><a>\x80 5</a>";
+        assert_eq!(
+            crate::from_bytes::<Doc>(windows1252).unwrap().a,
+            "\u{20ac} 5"
+        );
+
+        let utf8 = "<?xml version=\"1.0\" encoding=\"UTF-8\"?><a>\u{20ac}</a>";
+        assert_eq!(crate::from_str::<Doc>(utf8).unwrap().a, "\u{20ac}");
+
+        // Strings are already decoded, so what they declare is ignored
+        let decoded = "<?xml version=\"1.0\" encoding=\"ISO-8859-1\"?><a>caf\u{e9}</a>";
+        assert_eq!(crate::from_str::<Doc>(decoded).unwrap().a, "caf\u{e9}");
+        assert_eq!(
+            crate::from_string::<Doc>(decoded.to_string()).unwrap().a,
+            "caf\u{e9}"
+        );
+        let options = crate::DeserializerOptions::default();
+        assert_eq!(
+            crate::from_str_custom::<Doc>(decoded, options).unwrap().a,
+            "caf\u{e9}"
+        );
+    }
+
+    #[test]
+    fn deserialize_error_details() {
+        use std::error::Error;
+        use xml::reader::XmlEvent;
+
+        #[derive(Debug, Deserialize)]
+        struct Doc {
+            #[serde(rename = "doc")]
+            _doc: Inner,
+        }
+
+        #[derive(Debug, Deserialize)]
+        struct Inner {
+            #[serde(rename = "a")]
+            _a: u32,
+        }
+
+        let start = |name: &str| {
+            Ok(XmlEvent::StartElement {
+                name: xml::name::OwnedName::local(name),
+                attributes: vec![],
+                namespace: xml::namespace::Namespace::empty(),
+            })
+        };
+        let end = |name: &str| {
+            Ok(XmlEvent::EndElement {
+                name: xml::name::OwnedName::local(name),
+            })
+        };
+        let events = vec![
+            start("doc"),
+            start("a"),
+            Ok(XmlEvent::Characters("1".to_string())),
+            end("b"),
+            end("doc"),
+            Ok(XmlEvent::EndDocument),
+        ];
+        let err = crate::from_events::<Doc>(&events).unwrap_err();
+        match err.inner() {
+            crate::Error::MismatchedElement { expected, found } => {
+                assert_eq!(**expected, xml::name::OwnedName::local("a"));
+                assert_eq!(**found, xml::name::OwnedName::local("b"));
+            }
+            err => panic!("unexpected error {:?}", err),
+        }
+
+        let err = crate::from_str::<Doc>("<doc><a>x</a></doc>").unwrap_err();
+        assert!(matches!(
+            err.source().and_then(|e| e.downcast_ref::<crate::Error>()),
+            Some(crate::Error::ExpectedInt { found }) if found == "x"
+        ));
+
+        #[derive(Debug, Deserialize)]
+        enum Inline {
+            #[serde(rename = "$text")]
+            #[allow(dead_code)]
+            Text(String, String),
+        }
+
+        #[derive(Debug, Deserialize)]
+        struct Paragraph {
+            #[serde(rename = "$mixed")]
+            _content: Vec<Inline>,
+        }
+
+        #[derive(Debug, Deserialize)]
+        struct Text {
+            #[serde(rename = "p")]
+            _p: Paragraph,
+        }
+
+        let err = crate::from_str::<Text>("<p>a</p>").unwrap_err();
+        assert!(matches!(
+            err.inner(),
+            crate::Error::Unsupported {
+                method: "tuple_variant"
+            }
+        ));
+    }
+
+    #[test]
+    fn deserialize_mixed_whitespace() {
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        enum Inline {
+            #[serde(rename = "$text")]
+            Text(String),
+            #[serde(rename = "b")]
+            Bold(String),
+        }
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Paragraph {
+            #[serde(rename = "$mixed")]
+            content: Vec<Inline>,
+        }
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Doc {
+            #[serde(rename = "p")]
+            p: Paragraph,
+        }
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Div {
+            #[serde(rename = "p")]
+            p: Vec<Paragraph>,
+        }
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Page {
+            #[serde(rename = "div")]
+            div: Div,
+        }
+
+        let text = |s: &str| Inline::Text(s.to_string());
+        let bold = |s: &str| Inline::Bold(s.to_string());
+        let options = || crate::Options {
+            include_schema_location: false,
+            write_document_declaration: false,
+            ..Default::default()
+        };
+
+        let xml = "<p>Hello <b>world</b>!</p>";
+        let value = Doc {
+            p: Paragraph {
+                content: vec![text("Hello "), bold("world"), text("!")],
+            },
+        };
+        assert_eq!(crate::from_str::<Doc>(xml).unwrap(), value);
+        assert_eq!(crate::to_string_custom(&value, options()).unwrap(), xml);
+
+        let value = Page {
+            div: Div {
+                p: vec![
+                    Paragraph {
+                        content: vec![text("Hello "), bold("world"), text(" !")],
+                    },
+                    Paragraph {
+                        content: vec![bold("a"), text(" b\n  c "), bold("d")],
+                    },
+                ],
+            },
+        };
+        let xml = crate::to_string_custom(&value, options()).unwrap();
+        assert_eq!(
+            xml,
+            "<div>\n  <p>Hello <b>world</b> !</p>\n  <p><b>a</b> b\n  c <b>d</b></p>\n</div>"
+        );
+        assert_eq!(crate::from_str::<Page>(&xml).unwrap(), value);
+    }
+
+    #[test]
+    fn deserialize_mixed_with_fields() {
+        #[derive(Debug, PartialEq, Deserialize)]
+        enum Inline {
+            #[serde(rename = "$text")]
+            Text(String),
+            #[serde(rename = "b")]
+            Bold(String),
+        }
+
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Paragraph {
+            #[serde(rename = "$mixed")]
+            content: Vec<Inline>,
+        }
+
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Doc {
+            #[serde(rename = "p")]
+            p: Paragraph,
+        }
+
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Quote {
+            #[serde(rename = "t")]
+            title: String,
+            #[serde(rename = "$mixed")]
+            content: Vec<Inline>,
+        }
+
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Quoted {
+            #[serde(rename = "q")]
+            q: Quote,
+        }
+
+        for xml in ["<p></p>", "<p/>"] {
+            let doc = crate::from_str::<Doc>(xml).unwrap();
+            assert_eq!(doc.p.content, vec![]);
+        }
+
+        let doc = crate::from_str::<Quoted>("<q><t>title</t>hi <b>x</b></q>").unwrap();
+        assert_eq!(doc.q.title, "title");
+        assert_eq!(
+            doc.q.content,
+            vec![Inline::Text("hi ".to_string()), Inline::Bold("x".to_string())]
+        );
+
+        let doc = crate::from_str::<Quoted>("<q>hi <b>x</b><t>title</t></q>").unwrap();
+        assert_eq!(doc.q.title, "title");
+        assert_eq!(doc.q.content.len(), 2);
+
+        let doc = crate::from_str::<Quoted>("<q><t>title</t></q>").unwrap();
+        assert_eq!(doc.q.content, vec![]);
+    }
+
+    #[test]
+    fn deserialize_error_location() {
+        #[derive(Debug, Deserialize)]
+        struct Doc {
+            #[serde(rename = "{urn:foo}foo")]
+            _foo: Foo,
+        }
+
+        #[derive(Debug, Deserialize)]
+        struct Foo {
+            #[serde(rename = "{urn:foo}bar")]
+            _bar: Bar,
+        }
+
+        #[derive(Debug, Deserialize)]
+        struct Bar {
+            #[serde(rename = "{urn:bar}bar:count")]
+            _count: u32,
+        }
+
+        let err = crate::from_str::<Doc>(
+            r#"<?xml version="1.0" encoding="utf-8"?>
+<foo xmlns="urn:foo">
+  <bar>
+    <bar:count xmlns:bar="urn:bar">many</bar:count>
+  </bar>
+</foo>"#,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            r#"line 4 col 36 at /foo/bar/bar:count: expected a number, found "many""#
+        );
+        assert!(matches!(
+            err.inner(),
+            crate::Error::ExpectedInt { found } if found == "many"
+        ));
+
+        let err =
+            crate::from_str::<Doc>(r#"<?xml version="1.0"?><foo xmlns="urn:foo">"#).unwrap_err();
+        let source = std::error::Error::source(&err).unwrap();
+        assert!(matches!(
+            source.downcast_ref::<crate::Error>(),
+            Some(crate::Error::XMLRError(_))
+        ));
+        assert!(source.source().unwrap().is::<xml::reader::Error>());
+    }
+
+    #[test]
+    fn deserialize_unknown_fields() {
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Doc {
+            #[serde(rename = "{urn:foo}foo")]
+            foo: Foo,
+        }
+
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Foo {
+            #[serde(rename = "$attr:a")]
+            a: String,
+            #[serde(rename = "{urn:foo}bar")]
+            bar: String,
+        }
+
+        let xml = r#"<?xml version="1.0" encoding="utf-8"?>
+<foo xmlns="urn:foo" xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance" a="1" b="2"
+     xsi:schemaLocation="urn:foo foo.xsd">
+  <bar>x</bar>
+  <baz>y</baz>
+</foo>"#;
+        let expected = Doc {
+            foo: Foo {
+                a: "1".to_string(),
+                bar: "x".to_string(),
+            },
+        };
+
+        assert_eq!(crate::from_str::<Doc>(xml).unwrap(), expected);
+
+        let reported = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+        let options = crate::DeserializerOptions {
+            unknown_fields: crate::UnknownFields::Report({
+                let reported = reported.clone();
+                std::sync::Arc::new(move |err| reported.lock().unwrap().push(err.to_string()))
+            }),
+            ..Default::default()
+        };
+        assert_eq!(
+            crate::from_str_custom::<Doc>(xml, options).unwrap(),
+            expected
+        );
+        assert_eq!(
+            *reported.lock().unwrap(),
+            vec![
+                "line 2 col 1 at /foo: unknown attribute b",
+                "line 5 col 3 at /foo: unknown element {urn:foo}baz",
+            ]
+        );
+
+        let options = crate::DeserializerOptions {
+            unknown_fields: crate::UnknownFields::Deny,
+            ..Default::default()
+        };
+        let err =
+            crate::from_str_custom::<Doc>(&xml.replace(r#" b="2""#, ""), options).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "line 5 col 3 at /foo: unknown element {urn:foo}baz"
+        );
+    }
+
+    #[test]
+    fn deserialize_interleaved_sequences() {
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Doc {
+            #[serde(rename = "foo")]
+            foo: Foo,
+        }
+
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Foo {
+            #[serde(rename = "a")]
+            a: Vec<u32>,
+            #[serde(rename = "b")]
+            b: Bar,
+            #[serde(rename = "c", default)]
+            c: Vec<String>,
+        }
+
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Bar {
+            #[serde(rename = "c")]
+            c: String,
+        }
+
+        let xml = r#"<?xml version="1.0" encoding="utf-8"?>
+<foo><a>1</a><c>x</c><b><c>y</c></b><a>2</a><c>z</c><a>3</a></foo>"#;
+
+        assert!(crate::from_str::<Doc>(xml).is_err());
+
+        let options = crate::DeserializerOptions {
+            interleaved_sequences: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            crate::from_str_custom::<Doc>(xml, options).unwrap(),
+            Doc {
+                foo: Foo {
+                    a: vec![1, 2, 3],
+                    b: Bar { c: "y".to_string() },
+                    c: vec!["x".to_string(), "z".to_string()],
+                }
+            }
+        );
+    }
+
+    #[test]
+    fn deserialize_borrowed_strings() {
+        use std::borrow::Cow;
+
+        #[derive(Debug, Deserialize)]
+        struct Doc<'a> {
+            #[serde(rename = "item", borrow)]
+            item: Item<'a>,
+        }
+
+        #[derive(Debug, Deserialize)]
+        struct Item<'a> {
+            #[serde(rename = "$attr:id")]
+            id: &'a str,
+            #[serde(rename = "$attr:title", borrow)]
+            title: Cow<'a, str>,
+            #[serde(rename = "name")]
+            name: &'a str,
+            #[serde(rename = "note", borrow)]
+            note: Cow<'a, str>,
+            #[serde(rename = "code", borrow)]
+            code: Cow<'a, str>,
+        }
+
+        let xml = r#"<item id="é1" title="a &amp; b">
+  <name>
+    ünï
+  </name>
+  <note>x &lt; y</note>
+  <code><![CDATA[<p>]]></code>
+</item>"#;
+        let within = |s: &str| xml.as_bytes().as_ptr_range().contains(&s.as_ptr());
+
+        let item = crate::from_str::<Doc>(xml).unwrap().item;
+        assert_eq!((item.id, item.name), ("é1", "ünï"));
+        assert!(within(item.id) && within(item.name));
+        assert!(matches!(item.title, Cow::Owned(ref title) if title == "a & b"));
+        assert!(matches!(item.note, Cow::Owned(ref note) if note == "x < y"));
+        assert!(matches!(item.code, Cow::Borrowed("<p>")));
+
+        let item = crate::from_slice::<Doc>(xml.as_bytes()).unwrap().item;
+        assert!(within(item.id) && within(item.name));
+
+        assert!(crate::from_str::<Doc>(&xml.replace("ünï", "&#252;nï")).is_err());
+    }
 }
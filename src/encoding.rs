@@ -0,0 +1,430 @@
+//! Character encodings other than UTF-8
+//!
+//! The parser only reads UTF-8, UTF-16 with a byte order mark and ISO-8859-1 itself, so documents
+//! in any other encoding are transcoded to UTF-8 on the way in. The encoding is taken from the
+//! byte order mark, or else from the `encoding` of the XML declaration. Documents given as strings
+//! are already decoded, so are read as UTF-8 whatever they declare. On the way out, documents are
+//! transcoded from UTF-8 to the encoding named in the serializer's options.
+//!
+//! The Encoding Standard takes ISO-8859-1 to mean windows-1252, as web browsers do, but here it
+//! means ISO-8859-1 itself.
+
+use std::io::{Read, Write};
+
+/// How many bytes to look through for the XML declaration
+const SNIFF_LEN: usize = 1024;
+
+/// Labels of ISO-8859-1, which the Encoding Standard treats as windows-1252
+const LATIN1_LABELS: &[&str] = &[
+    "iso-8859-1",
+    "iso8859-1",
+    "iso88591",
+    "iso_8859-1",
+    "iso_8859-1:1987",
+    "latin1",
+    "l1",
+    "cp819",
+    "ibm819",
+    "iso-ir-100",
+    "csisolatin1",
+];
+
+fn is_latin1(label: &[u8]) -> bool {
+    let label = label.trim_ascii();
+    LATIN1_LABELS
+        .iter()
+        .any(|latin1| latin1.as_bytes().eq_ignore_ascii_case(label))
+}
+
+/// How a document is to be read, worked out from its first bytes
+enum Sniffed {
+    /// UTF-8, or an encoding the parser does not know about, which it is left to deal with
+    Parser,
+    /// ISO-8859-1, which the parser reads itself
+    Latin1,
+    Transcode(&'static encoding_rs::Encoding),
+}
+
+fn sniff(prefix: &[u8]) -> Sniffed {
+    let encoding = match encoding_rs::Encoding::for_bom(prefix) {
+        Some((encoding, _)) => Some(encoding),
+        None => match prefix {
+            [0x3C, 0x00, 0x3F, 0x00, ..] => Some(encoding_rs::UTF_16LE),
+            [0x00, 0x3C, 0x00, 0x3F, ..] => Some(encoding_rs::UTF_16BE),
+            _ => match declared_encoding(prefix) {
+                Some(label) if is_latin1(label) => return Sniffed::Latin1,
+                Some(label) => encoding_rs::Encoding::for_label(label),
+                None => None,
+            },
+        },
+    };
+    match encoding {
+        Some(encoding) if encoding != encoding_rs::UTF_8 => Sniffed::Transcode(encoding),
+        _ => Sniffed::Parser,
+    }
+}
+
+/// The `encoding` given in the XML declaration at the start of an ASCII compatible document
+fn declared_encoding(prefix: &[u8]) -> Option<&[u8]> {
+    let declaration = prefix.strip_prefix(b"<?xml")?;
+    let end = declaration.windows(2).position(|w| w == b"?>")?;
+    let declaration = &declaration[..end];
+    let start = declaration.windows(8).position(|w| w == b"encoding")? + 8;
+    let rest = declaration[start..].trim_ascii_start().strip_prefix(b"=")?;
+    let rest = rest.trim_ascii_start();
+    let quote = *rest.first()?;
+    if quote != b'"' && quote != b'\'' {
+        return None;
+    }
+    let value = &rest[1..];
+    Some(&value[..value.iter().position(|&b| b == quote)?])
+}
+
+/// Input transcoded to UTF-8
+pub(crate) struct DecodingRead<R: Read> {
+    inner: R,
+    decoder: Option<encoding_rs::Decoder>,
+    latin1: bool,
+    /// Bytes read from `inner` but not yet decoded
+    input: Vec<u8>,
+    input_pos: usize,
+    /// Decoded bytes not yet handed out
+    output: Vec<u8>,
+    output_pos: usize,
+    eof: bool,
+}
+
+impl<R: Read> DecodingRead<R> {
+    /// Look at the start of a document to see whether it needs transcoding
+    pub(crate) fn new(mut inner: R) -> std::io::Result<Self> {
+        // Stop at the end of the first tag, so as not to wait on a stream for more than that
+        let mut prefix = vec![0; SNIFF_LEN];
+        let mut len = 0;
+        while len < SNIFF_LEN && !prefix[..len].contains(&b'>') {
+            match inner.read(&mut prefix[len..]) {
+                Ok(0) => break,
+                Ok(read) => len += read,
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+        prefix.truncate(len);
+        let sniffed = sniff(&prefix);
+        let decoder = match sniffed {
+            Sniffed::Transcode(encoding) => {
+                trace!("transcoding input from {}", encoding.name());
+                Some(encoding.new_decoder_with_bom_removal())
+            }
+            _ => None,
+        };
+        Ok(DecodingRead {
+            inner,
+            decoder,
+            latin1: matches!(sniffed, Sniffed::Latin1),
+            input: prefix,
+            input_pos: 0,
+            output: vec![],
+            output_pos: 0,
+            eof: false,
+        })
+    }
+
+    /// Input that is already UTF-8, passed through as it is
+    pub(crate) fn utf8(inner: R) -> Self {
+        DecodingRead {
+            inner,
+            decoder: None,
+            latin1: false,
+            input: vec![],
+            input_pos: 0,
+            output: vec![],
+            output_pos: 0,
+            eof: false,
+        }
+    }
+
+    /// The encoding the parser should read the document in, whatever it declares, if it
+    /// cannot go by the declaration
+    pub(crate) fn parser_encoding(&self) -> Option<xml::Encoding> {
+        match (&self.decoder, self.latin1) {
+            (Some(_), _) => Some(xml::Encoding::Utf8),
+            (None, true) => Some(xml::Encoding::Latin1),
+            (None, false) => None,
+        }
+    }
+}
+
+impl<R: Read> Read for DecodingRead<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let decoder = match &mut self.decoder {
+            Some(decoder) => decoder,
+            None if self.input_pos < self.input.len() => {
+                let len = buf.len().min(self.input.len() - self.input_pos);
+                buf[..len].copy_from_slice(&self.input[self.input_pos..self.input_pos + len]);
+                self.input_pos += len;
+                return Ok(len);
+            }
+            None => return self.inner.read(buf),
+        };
+        while self.output_pos == self.output.len() {
+            if self.eof {
+                return Ok(0);
+            }
+            if self.input_pos == self.input.len() {
+                self.input.resize(8192, 0);
+                let read = self.inner.read(&mut self.input)?;
+                self.input.truncate(read);
+                self.input_pos = 0;
+                self.eof = read == 0;
+            }
+            let input = &self.input[self.input_pos..];
+            self.output.clear();
+            self.output_pos = 0;
+            self.output.reserve(
+                decoder
+                    .max_utf8_buffer_length(input.len())
+                    .unwrap_or(input.len() * 3 + 16),
+            );
+            let mut output = String::from_utf8(std::mem::take(&mut self.output)).unwrap();
+            let (_, read, _) = decoder.decode_to_string(input, &mut output, self.eof);
+            self.output = output.into_bytes();
+            self.input_pos += read;
+        }
+        let len = buf.len().min(self.output.len() - self.output_pos);
+        buf[..len].copy_from_slice(&self.output[self.output_pos..self.output_pos + len]);
+        self.output_pos += len;
+        Ok(len)
+    }
+}
+
+/// The characters an output encoding can represent, for the serializer to write the rest of the
+/// text and attribute values as character references
+pub(crate) enum Charset {
+    Unicode,
+    Latin1,
+    Other(&'static encoding_rs::Encoding),
+}
+
+impl Charset {
+    /// The characters of the encoding with the given label; unknown labels are left for
+    /// `Output::new` to reject
+    pub(crate) fn for_label(label: &str) -> Self {
+        if is_latin1(label.as_bytes()) {
+            return Charset::Latin1;
+        }
+        match encoding_rs::Encoding::for_label(label.as_bytes()) {
+            Some(encoding) if encoding.output_encoding() == encoding => Charset::Other(encoding),
+            _ => Charset::Unicode,
+        }
+    }
+
+    pub(crate) fn encodes(&self, c: char) -> bool {
+        match self {
+            Charset::Unicode => true,
+            Charset::Latin1 => u8::try_from(c).is_ok(),
+            // Every encoding there is output for takes ASCII as it is
+            Charset::Other(_) if c.is_ascii() => true,
+            Charset::Other(encoding) => {
+                let mut output = [0; 16];
+                let (result, _, _) = encoding.new_encoder().encode_from_utf8_without_replacement(
+                    c.encode_utf8(&mut [0; 4]),
+                    &mut output,
+                    true,
+                );
+                matches!(result, encoding_rs::EncoderResult::InputEmpty)
+            }
+        }
+    }
+}
+
+/// Output transcoded from UTF-8
+///
+/// Characters the encoding lacks are an error, as the serializer has already written those it
+/// can as character references, and they cannot go anywhere else in markup.
+pub(crate) struct EncodingWrite<W: Write> {
+    inner: W,
+    encoder: Encoder,
+    /// The end of a character split across writes
+    pending: Vec<u8>,
+}
+
+enum Encoder {
+    Utf16 { big_endian: bool },
+    Latin1,
+    Other(encoding_rs::Encoder),
+}
+
+/// The encoder for the given label, or `None` for UTF-8, which needs no transcoding
+fn output_encoder(label: &str) -> crate::Result<Option<Encoder>> {
+    if is_latin1(label.as_bytes()) {
+        return Ok(Some(Encoder::Latin1));
+    }
+    match encoding_rs::Encoding::for_label(label.as_bytes()) {
+        Some(encoding) if encoding == encoding_rs::UTF_8 => Ok(None),
+        Some(encoding) if encoding == encoding_rs::UTF_16LE || encoding == encoding_rs::UTF_16BE => {
+            Ok(Some(Encoder::Utf16 {
+                big_endian: encoding == encoding_rs::UTF_16BE,
+            }))
+        }
+        Some(encoding) => Ok(Some(Encoder::Other(encoding.new_encoder()))),
+        None => Err(crate::Error::UnknownEncoding {
+            label: label.to_string(),
+        }),
+    }
+}
+
+/// Whether the given label names UTF-8
+pub(crate) fn is_utf8(label: &str) -> bool {
+    encoding_rs::Encoding::for_label(label.as_bytes()) == Some(encoding_rs::UTF_8)
+}
+
+/// Output in the encoding with the given label, transcoded unless that is UTF-8
+pub(crate) enum Output<W: Write> {
+    Utf8(W),
+    Encoded(EncodingWrite<W>),
+}
+
+impl<W: Write> Output<W> {
+    pub(crate) fn new(inner: W, label: &str) -> crate::Result<Self> {
+        match output_encoder(label)? {
+            Some(encoder) => EncodingWrite::new(inner, encoder)
+                .map(Output::Encoded)
+                .map_err(|err| crate::Error::XMLWError(err.into())),
+            None => Ok(Output::Utf8(inner)),
+        }
+    }
+
+    pub(crate) fn into_inner(self) -> W {
+        match self {
+            Output::Utf8(inner) => inner,
+            // Any error has already been seen by the last flush
+            Output::Encoded(mut writer) => {
+                let _ = writer.finish();
+                writer.inner
+            }
+        }
+    }
+}
+
+impl<W: Write> Write for Output<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Output::Utf8(inner) => inner.write(buf),
+            Output::Encoded(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Output::Utf8(inner) => inner.flush(),
+            Output::Encoded(writer) => writer.flush(),
+        }
+    }
+}
+
+impl<W: Write> EncodingWrite<W> {
+    fn new(inner: W, encoder: Encoder) -> std::io::Result<Self> {
+        let mut writer = EncodingWrite {
+            inner,
+            encoder,
+            pending: vec![],
+        };
+        // UTF-16 cannot be read without a byte order mark, unless its endianness is known
+        // beforehand
+        if let Encoder::Utf16 { .. } = writer.encoder {
+            writer.encode("\u{FEFF}")?;
+        }
+        Ok(writer)
+    }
+
+    fn encode(&mut self, s: &str) -> std::io::Result<()> {
+        match &mut self.encoder {
+            Encoder::Utf16 { big_endian } => {
+                let bytes = s
+                    .encode_utf16()
+                    .flat_map(|unit| match big_endian {
+                        true => unit.to_be_bytes(),
+                        false => unit.to_le_bytes(),
+                    })
+                    .collect::<Vec<_>>();
+                self.inner.write_all(&bytes)
+            }
+            Encoder::Latin1 => {
+                let bytes = s
+                    .chars()
+                    .map(|c| u8::try_from(c).map_err(|_| unmappable(c, "ISO-8859-1")))
+                    .collect::<std::io::Result<Vec<_>>>()?;
+                self.inner.write_all(&bytes)
+            }
+            Encoder::Other(encoder) => Self::encode_other(&mut self.inner, encoder, s, false),
+        }
+    }
+
+    fn encode_other(
+        inner: &mut W,
+        encoder: &mut encoding_rs::Encoder,
+        mut s: &str,
+        last: bool,
+    ) -> std::io::Result<()> {
+        let mut output = Vec::with_capacity(
+            encoder
+                .max_buffer_length_from_utf8_without_replacement(s.len())
+                .unwrap_or(s.len())
+                + 16,
+        );
+        loop {
+            let (result, read) =
+                encoder.encode_from_utf8_to_vec_without_replacement(s, &mut output, last);
+            inner.write_all(&output)?;
+            output.clear();
+            s = &s[read..];
+            match result {
+                encoding_rs::EncoderResult::InputEmpty => return Ok(()),
+                encoding_rs::EncoderResult::OutputFull => output.reserve(s.len() + 16),
+                encoding_rs::EncoderResult::Unmappable(c) => {
+                    return Err(unmappable(c, encoder.encoding().name()))
+                }
+            }
+        }
+    }
+
+    /// Bring a stateful encoding such as ISO-2022-JP back to its initial state, so that what has
+    /// been written so far can be read on its own
+    fn finish(&mut self) -> std::io::Result<()> {
+        if let Encoder::Other(encoder) = &mut self.encoder {
+            Self::encode_other(&mut self.inner, encoder, "", true)?;
+            *encoder = encoder.encoding().new_encoder();
+        }
+        Ok(())
+    }
+}
+
+fn unmappable(c: char, encoding: &str) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!(
+            "{:?} cannot be written in {} outside text and attribute values",
+            c, encoding
+        ),
+    )
+}
+
+impl<W: Write> Write for EncodingWrite<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.pending.extend_from_slice(buf);
+        let valid = match std::str::from_utf8(&self.pending) {
+            Ok(s) => s.len(),
+            Err(e) if e.error_len().is_none() => e.valid_up_to(),
+            Err(e) => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, e)),
+        };
+        let pending = std::mem::take(&mut self.pending);
+        self.encode(std::str::from_utf8(&pending[..valid]).unwrap())?;
+        self.pending = pending[valid..].to_vec();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.finish()?;
+        self.inner.flush()
+    }
+}
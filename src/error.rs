@@ -1,18 +1,79 @@
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum Error {
     XMLWError(xml::writer::Error),
     XMLRError(xml::reader::Error),
     Message(String),
     ExpectedString,
-    ExpectedChar,
-    ExpectedBool,
-    ExpectedInt,
+    /// Text that is not exactly one character
+    ExpectedChar {
+        found: String,
+    },
+    /// Text that is not one of `true`, `false`, `1`, `0`, `y` or `n`
+    ExpectedBool {
+        found: String,
+    },
+    /// Text that does not parse as the requested integer or float type
+    ExpectedInt {
+        found: String,
+    },
+    /// Text that does not decode in the field's binary encoding
+    ExpectedBinary {
+        found: String,
+    },
     ExpectedElement,
-    Unsupported,
+    /// An end tag that does not close the element currently open
+    MismatchedElement {
+        expected: Box<xml::name::OwnedName>,
+        found: Box<xml::name::OwnedName>,
+    },
+    /// An element that matches no field, with `UnknownFields::Deny`
+    UnknownElement {
+        name: Box<xml::name::OwnedName>,
+    },
+    /// An attribute that matches no field, with `UnknownFields::Deny`
+    UnknownAttribute {
+        name: Box<xml::name::OwnedName>,
+    },
+    /// An `xsi:type` that names none of the `$type:` variants of an enum, or is missing
+    UnknownType {
+        found: Option<String>,
+    },
+    /// A document that goes over one of the limits set in the deserializer's options, named by
+    /// `limit`, e.g. `max_depth`
+    LimitExceeded {
+        limit: &'static str,
+        max: usize,
+    },
+    /// An `encoding` in the serializer's options that names no known character encoding
+    UnknownEncoding {
+        label: String,
+    },
+    /// A serde data model method this format has no representation for
+    Unsupported {
+        method: &'static str,
+    },
+    /// An error raised while deserializing, with the position of the last event read and the
+    /// path of elements open at the time, e.g. `/epp/response/resData/domain:infData`
+    Located {
+        position: Option<xml::common::TextPosition>,
+        path: String,
+        error: Box<Error>,
+    },
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+impl Error {
+    /// The underlying error, looking through any location attached to it
+    pub fn inner(&self) -> &Error {
+        match self {
+            Error::Located { error, .. } => error.inner(),
+            error => error,
+        }
+    }
+}
+
 impl serde::ser::Error for Error {
     fn custom<T: std::fmt::Display>(msg: T) -> Self {
         Error::Message(msg.to_string())
@@ -32,16 +93,68 @@ impl std::fmt::Display for Error {
             Error::XMLWError(err) => formatter.write_str(&err.to_string()),
             Error::XMLRError(err) => formatter.write_str(&err.to_string()),
             Error::ExpectedString => formatter.write_str("expected a string"),
-            Error::ExpectedChar => formatter.write_str("expected a char"),
-            Error::ExpectedBool => formatter.write_str("expected a bool"),
-            Error::ExpectedInt => formatter.write_str("expected a number"),
+            Error::ExpectedChar { found } => {
+                write!(formatter, "expected a char, found {:?}", found)
+            }
+            Error::ExpectedBool { found } => {
+                write!(formatter, "expected a bool, found {:?}", found)
+            }
+            Error::ExpectedInt { found } => {
+                write!(formatter, "expected a number, found {:?}", found)
+            }
+            Error::ExpectedBinary { found } => {
+                write!(formatter, "expected binary data, found {:?}", found)
+            }
             Error::ExpectedElement => formatter.write_str("expected an element"),
-            Error::Unsupported => formatter.write_str("unsupported operation"),
+            Error::MismatchedElement { expected, found } => write!(
+                formatter,
+                "expected end of element {}, found end of element {}",
+                expected, found
+            ),
+            Error::UnknownElement { name } => write!(formatter, "unknown element {}", name),
+            Error::UnknownAttribute { name } => write!(formatter, "unknown attribute {}", name),
+            Error::UnknownType { found: Some(found) } => {
+                write!(formatter, "unknown xsi:type {:?}", found)
+            }
+            Error::UnknownType { found: None } => formatter.write_str("missing xsi:type"),
+            Error::LimitExceeded { limit, max } => {
+                write!(formatter, "document exceeds {} of {}", limit, max)
+            }
+            Error::UnknownEncoding { label } => write!(formatter, "unknown encoding {:?}", label),
+            Error::Unsupported { method } => {
+                write!(formatter, "unsupported operation: {}", method)
+            }
+            Error::Located {
+                position: Some(position),
+                path,
+                error,
+            } => write!(
+                formatter,
+                "line {} col {} at {}: {}",
+                position.row + 1,
+                position.column + 1,
+                path,
+                error
+            ),
+            Error::Located {
+                position: None,
+                path,
+                error,
+            } => write!(formatter, "at {}: {}", path, error),
         }
     }
 }
 
-impl std::error::Error for Error {}
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::XMLWError(err) => Some(err),
+            Error::XMLRError(err) => Some(err),
+            Error::Located { error, .. } => Some(error.as_ref()),
+            _ => None,
+        }
+    }
+}
 
 impl From<xml::writer::Error> for Error {
     fn from(err: xml::writer::Error) -> Self {
@@ -5,16 +5,29 @@ extern crate serde_derive;
 extern crate log;
 extern crate core;
 
+mod binary;
 mod de;
+mod encoding;
 mod error;
+mod raw;
 mod ser;
 mod tag;
 
 pub(crate) use tag::Tag;
 
-pub use de::{from_events, from_str, from_string, Deserializer};
+pub use binary::BinaryEncoding;
+pub use de::{
+    from_bytes, from_event_iter, from_events, from_reader, from_reader_custom, from_slice, from_str,
+    from_str_custom, from_string, Children, Deserializer, Options as DeserializerOptions, ReaderEvents,
+    UnknownFields,
+};
 pub use error::{Error, Result};
-pub use ser::{to_events, to_events_custom, to_string, to_string_custom, Options, Serializer};
+pub use raw::RawXml;
+pub use ser::{
+    to_event_writer, to_event_writer_custom, to_events, to_events_custom, to_string,
+    to_string_custom, to_writer, Escaping, FragmentWriter, Namespaces, NoneElements, Options,
+    SelfClosing,
+};
 
 #[cfg(test)]
 mod tests {
@@ -96,6 +109,61 @@ mod tests {
         };
 
         let encoded = ser::to_string(&message).expect("Encode to XML");
-        println!("{:?}", encoded);
+        // `$valueRaw` text goes in as markup, unescaped
+        assert_eq!(
+            encoded,
+            concat!(
+                "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+                "<epp xmlns=\"urn:ietf:params:xml:ns:epp-1.0\" xmlns:xsi=\"http://www.w3.org/2001/XMLSchema-instance\" xsi:schemaLocation=\"urn:ietf:params:xml:ns:epp-1.0 epp-1.0.xsd\">\n",
+                "  <command>&<clTRID>&amp;</clTRID>\n",
+                "  </command>\n",
+                "</epp>"
+            )
+        );
+
+        let hello = EPPMessage {
+            message: EPPMessageType::Hello {},
+        };
+        let encoded = ser::to_string(&hello).expect("Encode to XML");
+        assert_eq!(
+            encoded,
+            concat!(
+                "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+                "<epp xmlns=\"urn:ietf:params:xml:ns:epp-1.0\" xmlns:xsi=\"http://www.w3.org/2001/XMLSchema-instance\" xsi:schemaLocation=\"urn:ietf:params:xml:ns:epp-1.0 epp-1.0.xsd\">\n",
+                "  <hello/>\n",
+                "</epp>"
+            )
+        );
+
+        let login = EPPCommandType::Login(EPPLogin {
+            client_id: "client".to_string(),
+            password: "password".to_string(),
+            new_password: None,
+            options: EPPLoginOptions {
+                version: "1.0".to_string(),
+                language: "en".to_string(),
+            },
+            services: EPPLoginServices {
+                objects: vec!["urn:ietf:params:xml:ns:domain-1.0".to_string()],
+            },
+        });
+        let encoded = ser::to_string(&login).expect("Encode to XML");
+        assert_eq!(
+            encoded,
+            concat!(
+                "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+                "<login xmlns=\"urn:ietf:params:xml:ns:epp-1.0\" xmlns:xsi=\"http://www.w3.org/2001/XMLSchema-instance\" xsi:schemaLocation=\"urn:ietf:params:xml:ns:epp-1.0 epp-1.0.xsd\">\n",
+                "  <clID>client</clID>\n",
+                "  <pw>password</pw>\n",
+                "  <options>\n",
+                "    <version>1.0</version>\n",
+                "    <lang>en</lang>\n",
+                "  </options>\n",
+                "  <svcs>\n",
+                "    <objURI>urn:ietf:params:xml:ns:domain-1.0</objURI>\n",
+                "  </svcs>\n",
+                "</login>"
+            )
+        );
     }
 }
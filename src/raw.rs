@@ -0,0 +1,94 @@
+//! Subtrees kept as markup
+//!
+//! A `RawXml` field takes the whole element it matches, start and end tags included, as a string
+//! of XML, and writes that string back out as it is in place of an element. Its root element
+//! carries declarations for every namespace in scope where it was read, so the markup stands on
+//! its own.
+//!
+//! The markup is written out again from the parser's events, so it is not a byte-for-byte copy
+//! of the input. Text, whitespace between tags, comments and processing instructions are kept
+//! whatever the deserializer's options, but attribute quoting, whitespace inside tags, character
+//! references and empty elements come out normalised.
+
+/// Name the serializer and deserializer recognise `RawXml` by
+pub(crate) const RAW_XML: &str = "$RawXml";
+
+/// An element kept as a string of XML markup
+#[derive(Debug, Default, Clone, Eq, PartialEq, Hash)]
+pub struct RawXml(String);
+
+impl RawXml {
+    /// Wrap a string of markup, which is trusted to be well formed
+    pub fn new(xml: impl Into<String>) -> Self {
+        RawXml(xml.into())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn into_string(self) -> String {
+        self.0
+    }
+}
+
+impl From<String> for RawXml {
+    fn from(xml: String) -> Self {
+        RawXml(xml)
+    }
+}
+
+impl From<RawXml> for String {
+    fn from(xml: RawXml) -> Self {
+        xml.0
+    }
+}
+
+impl AsRef<str> for RawXml {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::fmt::Display for RawXml {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl serde::Serialize for RawXml {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_newtype_struct(RAW_XML, &self.0)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for RawXml {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        impl<'de> serde::de::Visitor<'de> for Visitor {
+            type Value = RawXml;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("an XML element")
+            }
+
+            fn visit_newtype_struct<D: serde::Deserializer<'de>>(
+                self,
+                deserializer: D,
+            ) -> Result<RawXml, D::Error> {
+                serde::Deserialize::deserialize(deserializer).map(RawXml)
+            }
+
+            fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<RawXml, E> {
+                Ok(RawXml(v.to_string()))
+            }
+
+            fn visit_string<E: serde::de::Error>(self, v: String) -> Result<RawXml, E> {
+                Ok(RawXml(v))
+            }
+        }
+
+        deserializer.deserialize_newtype_struct(RAW_XML, Visitor)
+    }
+}
@@ -3,39 +3,74 @@
 //! The special serde tag name `$value` equates to the inner value of an XML element.
 //! Tags starting with `$attr:` will be encoded as attributes rather than new elements.
 //! Namespaces and prefixes can be set using the tag name format `{namespace}prefix:tag-name`.
-
-use crate::Tag;
+//! Tags starting with `$hex:` or `$base64:` select the encoding of byte strings in that field.
+//! A `$mixed` field holds a sequence of the child nodes of an element, in order, as an enum with
+//! a `$text` variant for text runs and one variant per child element. Text runs keep their
+//! whitespace, and no indentation is added inside the element.
+//! Enum variants named `$type:{namespace}prefix:TypeName` are written as the content of the
+//! field's own element, with an `xsi:type` attribute naming the variant. A variant in no
+//! namespace puts its element under a prefix, and is an error inside a default namespace.
+//!
+//! Output is streamed: XML events are written as the serde callbacks happen, without building
+//! an intermediate tree of the whole document. To get the attributes of an element written
+//! before its children, each value that becomes an element is serialised twice, once to pick
+//! out its `$attr:` fields and once to write it, so `Serialize` implementations must produce the
+//! same data every time they are called.
+
+use crate::encoding::Charset;
+use crate::{BinaryEncoding, Tag};
 use serde::{ser, Serialize};
 use std::borrow::Cow;
-
-pub struct Serializer;
+use std::collections::BTreeMap;
 
 trait EventWriter {
-    fn write<'a, E: Into<xml::writer::XmlEvent<'a>>>(
-        &mut self,
-        event: E,
-    ) -> xml::writer::Result<()>;
+    fn write(&mut self, event: xml::writer::XmlEvent) -> xml::writer::Result<()>;
+
+    /// Write markup as it is, for a writer that escapes text itself
+    fn write_raw(&mut self, markup: &str) -> xml::writer::Result<()> {
+        self.write(xml::writer::XmlEvent::cdata(markup))
+    }
 }
 
-struct EmitterWriter<W: std::io::Write>(xml::writer::EventWriter<W>);
+struct EmitterWriter<'a, W: std::io::Write>(&'a mut xml::writer::EventWriter<W>);
 
-impl<W: std::io::Write> EventWriter for EmitterWriter<W> {
-    fn write<'a, E: Into<xml::writer::XmlEvent<'a>>>(
-        &mut self,
-        event: E,
-    ) -> xml::writer::Result<()> {
+impl<'a, W: std::io::Write> EventWriter for EmitterWriter<'a, W> {
+    fn write(&mut self, event: xml::writer::XmlEvent) -> xml::writer::Result<()> {
         self.0.write(event)
     }
+
+    fn write_raw(&mut self, markup: &str) -> xml::writer::Result<()> {
+        // Empty text gets the end of a pending start tag out, and tells the emitter that the
+        // element has content
+        self.0.write(xml::writer::XmlEvent::characters(""))?;
+        self.0.inner_mut().write_all(markup.as_bytes())?;
+        Ok(())
+    }
 }
 
-struct ListWriter(Vec<xml::reader::XmlEvent>);
+/// Writer collecting events as a reader would give them, with names resolved against the
+/// namespaces in scope
+struct ListWriter(Vec<xml::reader::XmlEvent>, xml::namespace::NamespaceStack);
+
+impl ListWriter {
+    fn resolve(&self, name: xml::name::Name, attr: bool) -> xml::name::OwnedName {
+        let mut name = name.to_owned();
+        // Unprefixed attributes are in no namespace, rather than the default one
+        name.namespace = match (&name.prefix, attr) {
+            (None, true) => None,
+            (prefix, _) => self
+                .1
+                .get(prefix.as_deref().unwrap_or(""))
+                .filter(|n| !n.is_empty())
+                .map(String::from),
+        };
+        name
+    }
+}
 
 impl EventWriter for ListWriter {
-    fn write<'a, E: Into<xml::writer::XmlEvent<'a>>>(
-        &mut self,
-        event: E,
-    ) -> xml::writer::Result<()> {
-        let re = match event.into() {
+    fn write(&mut self, event: xml::writer::XmlEvent) -> xml::writer::Result<()> {
+        let re = match event {
             xml::writer::XmlEvent::StartDocument {
                 version,
                 encoding,
@@ -55,17 +90,31 @@ impl EventWriter for ListWriter {
                 name,
                 attributes,
                 namespace,
-            } => xml::reader::XmlEvent::StartElement {
-                name: name.to_owned(),
-                attributes: (*attributes).iter().map(|a| a.to_owned()).collect(),
-                namespace: (*namespace).clone(),
-            },
-            xml::writer::XmlEvent::EndElement { name } => xml::reader::XmlEvent::EndElement {
-                name: match name {
-                    Some(n) => n.to_owned(),
+            } => {
+                self.1.push_empty();
+                for (prefix, uri) in namespace.as_ref() {
+                    self.1.put(prefix, uri);
+                }
+                xml::reader::XmlEvent::StartElement {
+                    name: self.resolve(name, false),
+                    attributes: attributes
+                        .iter()
+                        .map(|a| xml::attribute::OwnedAttribute {
+                            name: self.resolve(a.name, true),
+                            value: a.value.to_string(),
+                        })
+                        .collect(),
+                    namespace: self.1.squash(),
+                }
+            }
+            xml::writer::XmlEvent::EndElement { name } => {
+                let name = match name {
+                    Some(n) => self.resolve(n, false),
                     None => panic!("EndElement has no name"),
-                },
-            },
+                };
+                self.1.pop();
+                xml::reader::XmlEvent::EndElement { name }
+            }
             xml::writer::XmlEvent::CData(s) => xml::reader::XmlEvent::CData(s.into()),
             xml::writer::XmlEvent::Characters(s) => xml::reader::XmlEvent::Characters(s.into()),
             xml::writer::XmlEvent::Comment(s) => xml::reader::XmlEvent::Comment(s.into()),
@@ -74,20 +123,204 @@ impl EventWriter for ListWriter {
         self.0.push(re);
         Ok(())
     }
+
+    /// Add the events that markup is made of, as it is a whole element
+    fn write_raw(&mut self, markup: &str) -> xml::writer::Result<()> {
+        // Whitespace as its own event would be dropped by `from_events`
+        let reader = xml::ParserConfig::new()
+            .ignore_comments(false)
+            .whitespace_to_characters(true)
+            .create_reader(markup.as_bytes());
+        for event in reader {
+            match event.map_err(std::io::Error::other)? {
+                xml::reader::XmlEvent::StartDocument { .. } | xml::reader::XmlEvent::EndDocument => {}
+                event => self.0.push(event),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How elements without any content are written
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SelfClosing {
+    /// `<a/>`
+    Compact,
+    /// `<a />`
+    Padded,
+    /// `<a></a>`
+    Expanded,
+}
+
+/// Which characters are replaced by entity or character references
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Escaping {
+    /// Only what is required: `<`, `>` and `&`, plus `"` and whitespace other than spaces in
+    /// attribute values
+    Minimal,
+    /// As `Minimal`, and also `"` and `'` everywhere
+    All,
+    /// As `Minimal`, and also every non-ASCII character as a numeric character reference
+    NonAsciiNumeric,
+}
+
+impl Escaping {
+    /// Escape text or an attribute value, writing characters the output encoding lacks as
+    /// character references as well
+    fn escape<'s>(self, s: &'s str, attr: bool, charset: &Charset) -> Cow<'s, str> {
+        let needs_escaping = |c: char| match c {
+            '<' | '>' | '&' => true,
+            '"' | '\t' | '\n' | '\r' if attr => true,
+            '"' | '\'' => self == Escaping::All,
+            c if c.is_ascii() => false,
+            c => self == Escaping::NonAsciiNumeric || !charset.encodes(c),
+        };
+
+        if !s.chars().any(needs_escaping) {
+            return Cow::Borrowed(s);
+        }
+
+        let mut output = String::with_capacity(s.len());
+        for c in s.chars() {
+            if !needs_escaping(c) {
+                output.push(c);
+                continue;
+            }
+            match c {
+                '<' => output.push_str("&lt;"),
+                '>' => output.push_str("&gt;"),
+                '&' => output.push_str("&amp;"),
+                '"' => output.push_str("&quot;"),
+                '\'' => output.push_str("&apos;"),
+                c => output.push_str(&format!("&#x{:X};", c as u32)),
+            }
+        }
+        Cow::Owned(output)
+    }
 }
 
+/// Where namespace declarations are written
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Namespaces {
+    /// On every element using a namespace, even if it is already in scope
+    PerElement,
+    /// Only where a namespace is not already bound to the same prefix
+    Minimal,
+    /// Once on the root element for all namespaces in the document, falling back to `Minimal`
+    /// for prefixes bound to more than one namespace
+    Hoisted,
+}
+
+/// How elements holding an `Option` that is `None` are written
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum NoneElements {
+    /// `<a/>`
+    Empty,
+    /// Not at all
+    Omit,
+    /// `<a xsi:nil="true"/>`
+    Nil,
+}
+
+#[derive(Debug, Clone)]
 pub struct Options {
+    /// Add `xmlns:xsi` and `xsi:schemaLocation` attributes for the namespaces used
     pub include_schema_location: bool,
+    /// Schema locations by namespace, taking precedence over locations given in tags
+    pub schema_locations: BTreeMap<String, String>,
+    /// Use `<last segment of namespace>.xsd` as the schema location for namespaces that have no
+    /// location given
+    pub guess_schema_locations: bool,
+    /// Declare all schema locations in one `xsi:schemaLocation` attribute on the root element,
+    /// rather than on the first element using each namespace
+    pub root_schema_location: bool,
+    /// Value of `xsi:noNamespaceSchemaLocation` on the root element
+    pub no_namespace_schema_location: Option<String>,
+    /// Indent nested elements, one element per line
+    pub perform_indent: bool,
+    /// String used for each level of indentation
+    pub indent_string: Cow<'static, str>,
+    /// String used to separate lines when indenting
+    pub line_separator: Cow<'static, str>,
+    /// Write the `<?xml ...?>` declaration at the start of the document
+    pub write_document_declaration: bool,
+    /// XML version given in the declaration
+    pub version: xml::common::XmlVersion,
+    /// Encoding given in the declaration, and which `to_writer` transcodes the document to
+    ///
+    /// Any label known to the WHATWG Encoding Standard is accepted, e.g. `ISO-8859-1` or
+    /// `UTF-16LE`. Characters the encoding lacks are written as character references.
+    /// `to_string` always produces and declares UTF-8, whatever this says.
+    pub encoding: Cow<'static, str>,
+    /// Value of `standalone` in the declaration, omitted if `None`
+    pub standalone: Option<bool>,
+    /// How elements without any content are written
+    pub self_closing: SelfClosing,
+    /// Which characters are escaped in text and attribute values
+    pub escaping: Escaping,
+    /// Where namespace declarations are written
+    pub namespaces: Namespaces,
+    /// Encoding of byte strings in fields without a `$hex:` or `$base64:` tag prefix
+    pub binary_encoding: BinaryEncoding,
+    /// How elements holding `None` are written
+    pub none_elements: NoneElements,
 }
 
 impl Default for Options {
     fn default() -> Self {
         Self {
             include_schema_location: true,
+            schema_locations: BTreeMap::new(),
+            guess_schema_locations: true,
+            root_schema_location: false,
+            no_namespace_schema_location: None,
+            perform_indent: true,
+            indent_string: "  ".into(),
+            line_separator: "\n".into(),
+            write_document_declaration: true,
+            version: xml::common::XmlVersion::Version10,
+            encoding: "UTF-8".into(),
+            standalone: None,
+            self_closing: SelfClosing::Compact,
+            escaping: Escaping::Minimal,
+            namespaces: Namespaces::PerElement,
+            binary_encoding: BinaryEncoding::Hex,
+            none_elements: NoneElements::Empty,
         }
     }
 }
 
+impl Options {
+    /// Configuration for an `xml::writer::EventWriter` that values can be serialised into with
+    /// `to_event_writer`, following these options for indentation and empty elements
+    pub fn emitter_config(&self) -> xml::writer::EmitterConfig {
+        xml::writer::EmitterConfig::new()
+            .perform_indent(self.perform_indent)
+            .indent_string(self.indent_string.clone())
+            .line_separator(self.line_separator.clone())
+            .write_document_declaration(false)
+            .normalize_empty_elements(true)
+            .keep_element_names_stack(true)
+            .pad_self_closing(self.self_closing == SelfClosing::Padded)
+    }
+
+    /// Configuration for the writer of a whole document, which leaves escaping to the
+    /// serializer so that `escaping` is followed
+    fn document_emitter_config(&self) -> xml::writer::EmitterConfig {
+        let mut conf = xml::writer::EmitterConfig::new()
+            .perform_indent(self.perform_indent)
+            .indent_string(self.indent_string.clone())
+            .line_separator(self.line_separator.clone())
+            .write_document_declaration(false)
+            .normalize_empty_elements(true)
+            .cdata_to_characters(true)
+            .keep_element_names_stack(true)
+            .pad_self_closing(self.self_closing == SelfClosing::Padded);
+        conf.perform_escaping = false;
+        conf
+    }
+}
+
 /// Serialise serde item to XML
 ///
 /// # Arguments
@@ -104,34 +337,123 @@ where
 /// # Arguments
 /// * `value` - The value to be serialised
 /// * `options` - Custom options for the serializer
-pub fn to_string_custom<T>(value: &T, options: Options) -> Result<String, crate::Error>
+pub fn to_string_custom<T>(value: &T, mut options: Options) -> Result<String, crate::Error>
+where
+    T: Serialize,
+{
+    // A string is always UTF-8, so its declaration must say so
+    if !crate::encoding::is_utf8(&options.encoding) {
+        options.encoding = "UTF-8".into();
+    }
+    let mut output = Vec::new();
+    write_utf8(&mut output, value, options)?;
+    Ok(String::from_utf8(output).unwrap())
+}
+
+/// Serialise serde item to XML, writing it directly to an `io::Write`
+///
+/// Events are emitted as the value is serialised, so memory usage does not grow with the
+/// size of the document.
+///
+/// Values are serialised more than once: each value written as an element is visited first for
+/// its attributes and then for its content, and with `Namespaces::Hoisted` or
+/// `root_schema_location` the whole value is walked once more beforehand. A `Serialize`
+/// implementation that gives different output on each call, e.g. one that drains an iterator
+/// through a `RefCell`, will produce inconsistent XML.
+///
+/// # Arguments
+/// * `writer` - Where to write the XML document
+/// * `value` - The value to be serialised
+/// * `options` - Custom options for the serializer
+pub fn to_writer<W, T>(writer: W, value: &T, options: Options) -> Result<(), crate::Error>
+where
+    W: std::io::Write,
+    T: Serialize,
+{
+    let writer = crate::encoding::Output::new(writer, &options.encoding)?;
+    write_utf8(writer, value, options)
+}
+
+fn write_utf8<W, T>(writer: W, value: &T, options: Options) -> Result<(), crate::Error>
+where
+    W: std::io::Write,
+    T: Serialize,
+{
+    let mut event_writer = start_document(writer, &options)?;
+    let mut writer = EmitterWriter(&mut event_writer);
+    Serializer::new(&mut writer, options).serialize_root(value)?;
+    // Leaves a stateful output encoding in its initial state at the end of the document
+    std::io::Write::flush(event_writer.inner_mut())
+        .map_err(|err| crate::Error::XMLWError(err.into()))
+}
+
+/// Create an event writer, and write the declaration to it if the options ask for one
+fn start_document<W: std::io::Write>(
+    writer: W,
+    options: &Options,
+) -> Result<xml::writer::EventWriter<W>, crate::Error> {
+    let mut event_writer = options.document_emitter_config().create_writer(writer);
+    if options.write_document_declaration {
+        event_writer.write(xml::writer::XmlEvent::StartDocument {
+            version: options.version,
+            encoding: Some(&options.encoding),
+            standalone: options.standalone,
+        })?;
+    }
+    Ok(event_writer)
+}
+
+/// Serialise serde item into an `xml::writer::EventWriter` that is already in use
+///
+/// No declaration is written, so the value can go anywhere an element may, e.g. inside a
+/// document being built by hand. Text goes to the writer unescaped, for it to escape as it is
+/// configured to, so `Options::escaping` has no effect here; the markup of `RawXml` and
+/// `$valueRaw` fields is written straight to the writer's output.
+///
+/// # Arguments
+/// * `writer` - The writer to write the value's events to
+/// * `value` - The value to be serialised
+pub fn to_event_writer<W, T>(
+    writer: &mut xml::writer::EventWriter<W>,
+    value: &T,
+) -> Result<(), crate::Error>
+where
+    W: std::io::Write,
+    T: Serialize,
+{
+    to_event_writer_custom(writer, value, Options::default())
+}
+
+/// Serialise serde item into an `xml::writer::EventWriter` that is already in use, with custom
+/// options
+///
+/// The writer's own configuration decides indentation and the like, so the options that set up
+/// the emitter have no effect, other than through `Options::emitter_config`.
+///
+/// # Arguments
+/// * `writer` - The writer to write the value's events to
+/// * `value` - The value to be serialised
+/// * `options` - Custom options for the serializer
+pub fn to_event_writer_custom<W, T>(
+    writer: &mut xml::writer::EventWriter<W>,
+    value: &T,
+    options: Options,
+) -> Result<(), crate::Error>
 where
+    W: std::io::Write,
     T: Serialize,
 {
-    let mut conf = xml::writer::EmitterConfig::new()
-        .perform_indent(true)
-        .write_document_declaration(true)
-        .normalize_empty_elements(true)
-        .cdata_to_characters(true)
-        .keep_element_names_stack(true)
-        .pad_self_closing(false);
-    conf.perform_escaping = false;
-
-    let c = std::io::Cursor::new(Vec::new());
-    let mut writer = EmitterWriter(conf.create_writer(c));
-    let mut serializer = Serializer;
-    let val = value.serialize(&mut serializer)?;
-    let mut state = _SerializerState {
-        raw_output: false,
-        ns_stack: vec![],
-        include_schema_location: options.include_schema_location,
-    };
-    format_data(&mut writer, &val, &mut state)?;
-    Ok(String::from_utf8(writer.0.into_inner().into_inner()).unwrap())
+    let mut writer = EmitterWriter(writer);
+    let mut serializer = Serializer::new(&mut writer, options);
+    serializer.writer_escapes = true;
+    serializer.serialize_root(value)
 }
 
 /// Serialise serde item to a list of XML events
 ///
+/// The events are those a reader would give for the document, so text is not escaped and
+/// `RawXml` markup comes as the events it is made of, which `from_event_iter` reads back.
+///
 /// # Arguments
 /// * `value` - The value to be serialised
 pub fn to_events<T>(value: &T) -> Result<Vec<xml::reader::XmlEvent>, crate::Error>
@@ -153,305 +475,792 @@ pub fn to_events_custom<T>(
 where
     T: Serialize,
 {
-    let mut writer = ListWriter(vec![]);
-    let mut serializer = Serializer;
-    let val = value.serialize(&mut serializer)?;
-    let mut state = _SerializerState {
-        raw_output: false,
-        ns_stack: vec![],
-        include_schema_location: options.include_schema_location,
-    };
-    format_data(&mut writer, &val, &mut state)?;
+    let mut writer = ListWriter(vec![], xml::namespace::NamespaceStack::default());
+    let mut serializer = Serializer::new(&mut writer, options);
+    // Reader events hold text as it is, not as markup
+    serializer.writer_escapes = true;
+    serializer.serialize_root(value)?;
     Ok(writer.0)
 }
 
-#[derive(Debug)]
-pub enum _SerializerData {
-    CData(String),
-    String(String),
-    Seq(Vec<_SerializerData>),
-    Struct {
-        attrs: Vec<(Cow<'static, str>, String)>,
-        contents: Vec<(Cow<'static, str>, _SerializerData)>,
-    },
+/// Writes a document a piece at a time, for streaming protocols such as XMPP and for exports too
+/// large to hold as one value
+///
+/// `open_root` writes the start tag of the root element, `write` then serialises any number of
+/// values inside it one after another, each as `to_writer` would write a whole document, and
+/// `close_root` writes the end tag. Everything is flushed to the underlying writer as soon as it
+/// is written, the root's start tag included. The declaration, if the options ask for one, is
+/// written when the `FragmentWriter` is created.
+///
+/// `Namespaces::Hoisted` and `root_schema_location` are not supported, as the root's start tag
+/// is written before the values that would have to be declared on it are known.
+pub struct FragmentWriter<W: std::io::Write> {
+    writer: xml::writer::EventWriter<crate::encoding::Output<W>>,
+    options: Options,
+    /// Serializer state kept between values: the open roots and the namespaces they bind
+    ns_stack: Vec<String>,
+    ns_scope: Vec<(String, String)>,
+    elements: Vec<OpenElement>,
+    /// Whether nothing has gone in the root opened last yet
+    root_empty: bool,
+}
+
+impl<W: std::io::Write> FragmentWriter<W> {
+    pub fn new(writer: W, options: Options) -> Result<Self, crate::Error> {
+        if options.namespaces == Namespaces::Hoisted {
+            return Err(crate::Error::Message(
+                "Namespaces::Hoisted is not supported by FragmentWriter".to_string(),
+            ));
+        }
+        if options.include_schema_location && options.root_schema_location {
+            return Err(crate::Error::Message(
+                "root_schema_location is not supported by FragmentWriter".to_string(),
+            ));
+        }
+        let writer = crate::encoding::Output::new(writer, &options.encoding)?;
+        let mut fragment_writer = FragmentWriter {
+            writer: start_document(writer, &options)?,
+            options,
+            ns_stack: vec![],
+            ns_scope: vec![],
+            elements: vec![],
+            root_empty: false,
+        };
+        fragment_writer.flush()?;
+        Ok(fragment_writer)
+    }
+
+    /// Write the start tag of a root element, with the `$attr:` fields of `attrs` as its
+    /// attributes; `()` gives none
+    ///
+    /// # Arguments
+    /// * `tag` - The root's tag, as used in field names, e.g. `{urn:example}ex:root`
+    /// * `attrs` - A value whose `$attr:` fields become the root's attributes
+    pub fn open_root<T: ?Sized + Serialize>(
+        &mut self,
+        tag: &'static str,
+        attrs: &T,
+    ) -> Result<(), crate::Error> {
+        self.fill_root()?;
+        self.with_serializer(|ser| {
+            let attrs = attrs.serialize(AttrCollector(ser.options.binary_encoding))?;
+            ser.start_element(Tag::from_static(tag), &attrs)?;
+            // The emitter holds back the end of a start tag until it knows whether the element is
+            // empty, so write empty text to get it out
+            ser.writer
+                .write(xml::writer::XmlEvent::characters(""))
+                .map_err(Into::into)
+        })?;
+        self.root_empty = true;
+        Ok(())
+    }
+
+    /// Serialise a value inside the open root, or at the top level if none is open
+    pub fn write<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), crate::Error> {
+        self.fill_root()?;
+        self.with_serializer(|ser| ser.serialize_root(value))
+    }
+
+    /// Write the end tag of the root opened last
+    pub fn close_root(&mut self) -> Result<(), crate::Error> {
+        if self.elements.is_empty() {
+            return Err(crate::Error::Message(
+                "no root element to close".to_string(),
+            ));
+        }
+        self.root_empty = false;
+        self.with_serializer(|ser| ser.end_element())
+    }
+
+    /// Indent the first thing to go in the root opened last, as the text that got its start tag
+    /// out stops the emitter doing so; whitespace is left alone with `xml:space="preserve"`
+    fn fill_root(&mut self) -> Result<(), crate::Error> {
+        let preserve = self.elements.last().is_some_and(|e| e.preserve);
+        if std::mem::replace(&mut self.root_empty, false) && self.options.perform_indent && !preserve
+        {
+            let text = format!(
+                "{}{}",
+                self.options.line_separator, self.options.indent_string
+            );
+            self.writer
+                .write(xml::writer::XmlEvent::characters(&text))?;
+        }
+        Ok(())
+    }
+
+    /// Get back the underlying writer, leaving any roots still open unclosed
+    pub fn into_inner(self) -> W {
+        self.writer.into_inner().into_inner()
+    }
+
+    fn with_serializer<F>(&mut self, f: F) -> Result<(), crate::Error>
+    where
+        F: FnOnce(&mut Serializer) -> Result<(), crate::Error>,
+    {
+        let mut writer = EmitterWriter(&mut self.writer);
+        let mut ser = Serializer::new(&mut writer, self.options.clone());
+        ser.ns_stack = std::mem::take(&mut self.ns_stack);
+        ser.ns_scope = std::mem::take(&mut self.ns_scope);
+        ser.elements = std::mem::take(&mut self.elements);
+        let result = f(&mut ser);
+        self.ns_stack = ser.ns_stack;
+        self.ns_scope = ser.ns_scope;
+        self.elements = ser.elements;
+        result?;
+        self.flush()
+    }
+
+    fn flush(&mut self) -> Result<(), crate::Error> {
+        std::io::Write::flush(self.writer.inner_mut())
+            .map_err(|err| crate::Error::XMLWError(err.into()))
+    }
 }
 
-impl _SerializerData {
-    fn as_str(&self) -> String {
-        match self {
-            _SerializerData::CData(s) => s.clone(),
-            _SerializerData::String(s) => s.clone(),
-            _SerializerData::Seq(s) => s.iter().map(|d| d.as_str()).collect::<Vec<_>>().join(","),
-            _SerializerData::Struct { contents, .. } => contents
+/// Attributes of the element a value opens, as `(tag, value)` pairs
+type Attrs = Vec<(&'static str, String)>;
+
+const XSI_NS: &str = "http://www.w3.org/2001/XMLSchema-instance";
+const XSI_NIL: &str = "{http://www.w3.org/2001/XMLSchema-instance}xsi:nil";
+const XSI_TYPE: &str = "{http://www.w3.org/2001/XMLSchema-instance}xsi:type";
+
+/// Records every namespace binding used in a document, without writing anything
+struct NamespaceCollector(Vec<(String, String)>);
+
+impl EventWriter for NamespaceCollector {
+    fn write(&mut self, event: xml::writer::XmlEvent) -> xml::writer::Result<()> {
+        if let xml::writer::XmlEvent::StartElement {
+            attributes,
+            namespace,
+            ..
+        } = event
+        {
+            let bindings = namespace
                 .iter()
-                .map(|(_, d)| d.as_str())
-                .collect::<Vec<_>>()
-                .join(","),
+                .chain(attributes.iter().filter_map(|a| match a.name {
+                    xml::name::Name {
+                        prefix: Some(p),
+                        namespace: Some(n),
+                        ..
+                    } => Some((p, n)),
+                    _ => None,
+                }));
+            for (prefix, uri) in bindings {
+                if !self.0.iter().any(|(p, n)| p == prefix && n == uri) {
+                    self.0.push((prefix.to_string(), uri.to_string()));
+                }
+            }
         }
+        Ok(())
     }
 }
 
-struct _SerializerState {
+struct OpenElement {
+    name: String,
+    /// Whether the element added its namespace to `ns_stack`
+    pop_ns: bool,
+    /// How many namespace bindings the element added to `ns_scope`
+    bindings: usize,
+    /// The default namespace inside the element, empty if there is none
+    default_ns: String,
+    /// Whether whitespace is significant inside the element, as set by `xml:space`
+    preserve: bool,
+}
+
+/// Streaming XML serializer, writing events to the underlying writer as it goes
+pub(crate) struct Serializer<'w> {
+    writer: &'w mut dyn EventWriter,
+    options: Options,
     raw_output: bool,
+    /// Whether the writer escapes text itself, rather than taking it escaped
+    writer_escapes: bool,
+    /// Characters of the output encoding
+    charset: Charset,
+    /// Encoding of byte strings in the current field
+    binary: BinaryEncoding,
     ns_stack: Vec<String>,
-    include_schema_location: bool,
-}
-
-fn format_data<W: EventWriter>(
-    writer: &mut W,
-    val: &_SerializerData,
-    state: &mut _SerializerState,
-) -> Result<(), crate::Error> {
-    match val {
-        _SerializerData::CData(s) => {
-            writer.write(xml::writer::XmlEvent::cdata(&match state.raw_output {
-                true => s.to_string(),
-                false => xml::escape::escape_str_pcdata(s).to_string(),
-            }))?
-        }
-        _SerializerData::String(s) => {
-            writer.write(xml::writer::XmlEvent::characters(&match state.raw_output {
-                true => s.to_string(),
-                false => xml::escape::escape_str_pcdata(s).to_string(),
-            }))?
-        }
-        _SerializerData::Seq(s) => {
-            for d in s {
-                format_data(writer, d, state)?;
+    /// Namespace bindings currently in scope, as `(prefix, uri)` pairs
+    ns_scope: Vec<(String, String)>,
+    /// Namespace bindings to declare on the root element
+    hoisted: Vec<(String, String)>,
+    /// Schema locations of the namespaces seen so far, as `(namespace, location)` pairs
+    locations: Vec<(String, String)>,
+    /// Schema locations to declare on the root element
+    root_locations: Vec<(String, String)>,
+    elements: Vec<OpenElement>,
+    /// Whether nothing has been written since the last start element
+    is_empty: bool,
+}
+
+impl<'w> Serializer<'w> {
+    fn new(writer: &'w mut dyn EventWriter, options: Options) -> Self {
+        Self {
+            writer,
+            raw_output: false,
+            writer_escapes: false,
+            binary: options.binary_encoding,
+            charset: Charset::for_label(&options.encoding),
+            options,
+            ns_stack: vec![],
+            ns_scope: vec![],
+            hoisted: vec![],
+            locations: vec![],
+            root_locations: vec![],
+            elements: vec![],
+            is_empty: false,
+        }
+    }
+
+    /// Serialise a value as a whole document
+    fn serialize_root<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), crate::Error> {
+        if self.options.namespaces == Namespaces::Hoisted
+            || (self.options.include_schema_location && self.options.root_schema_location)
+        {
+            self.prepare(value)?;
+        }
+        self.serialize(value)
+    }
+
+    /// Walk a value without writing it, to find the namespace bindings and schema locations to
+    /// declare on the root element
+    ///
+    /// Only bindings whose prefix is never bound to a different namespace are hoisted.
+    fn prepare<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), crate::Error> {
+        let mut collector = NamespaceCollector(vec![]);
+        let mut serializer = Serializer::new(
+            &mut collector,
+            Options {
+                namespaces: Namespaces::PerElement,
+                root_schema_location: false,
+                ..self.options.clone()
+            },
+        );
+        serializer.serialize(value)?;
+        self.root_locations = serializer.locations;
+
+        if self.options.namespaces == Namespaces::Hoisted {
+            let bindings = collector.0;
+            self.hoisted = bindings
+                .iter()
+                .filter(|(prefix, _)| bindings.iter().filter(|(p, _)| p == prefix).count() == 1)
+                .cloned()
+                .collect();
+        }
+        Ok(())
+    }
+
+    /// Serialise a value as the content of the current element
+    fn serialize<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), crate::Error> {
+        let attrs = value.serialize(AttrCollector(self.options.binary_encoding))?;
+        value.serialize(ValueSerializer {
+            ser: self,
+            mode: Mode::Content,
+            attrs,
+        })
+    }
+
+    /// Serialise a struct field, map entry, or enum variant with the given tag
+    fn serialize_field<T: ?Sized + Serialize>(
+        &mut self,
+        tag: Cow<'static, str>,
+        value: &T,
+    ) -> Result<(), crate::Error> {
+        let (binary, tag) = match tag {
+            Cow::Borrowed(tag) => {
+                let (binary, tag) = BinaryEncoding::split_tag(tag);
+                (binary, Cow::Borrowed(tag))
+            }
+            Cow::Owned(tag) => {
+                let (binary, rest) = BinaryEncoding::split_tag(&tag);
+                (binary, Cow::Owned(rest.to_string()))
+            }
+        };
+        let old_binary = std::mem::replace(
+            &mut self.binary,
+            binary.unwrap_or(self.options.binary_encoding),
+        );
+
+        let res = if tag == "$valueRaw" {
+            let old_val = std::mem::replace(&mut self.raw_output, true);
+            let res = self.serialize(value);
+            self.raw_output = old_val;
+            res
+        } else if tag == "$mixed" {
+            // Indenting would add whitespace to the text in mixed content
+            let preserve = self
+                .elements
+                .last_mut()
+                .map(|e| std::mem::replace(&mut e.preserve, true));
+            let res = self
+                .keep_unindented()
+                .and_then(|_| self.serialize(value))
+                .and_then(|_| self.keep_unindented());
+            if let (Some(element), Some(preserve)) = (self.elements.last_mut(), preserve) {
+                element.preserve = preserve;
+            }
+            res
+        } else if tag.starts_with("$value") {
+            self.serialize(value)
+        } else {
+            let attrs = value.serialize(AttrCollector(self.options.binary_encoding))?;
+            value.serialize(ValueSerializer {
+                ser: self,
+                mode: Mode::Element { tag, in_seq: false },
+                attrs,
+            })
+        };
+        self.binary = old_binary;
+        res
+    }
+
+    /// Schema location for the namespace of a tag; the registry in the options takes precedence
+    /// over a location given in the tag, which takes precedence over a guessed `<name>.xsd`
+    fn schema_location(&self, tag: &Tag) -> Option<String> {
+        let n = tag.n?;
+        if let Some(l) = self.options.schema_locations.get(n) {
+            return Some(l.clone());
+        }
+        match tag.l {
+            Some(l) if !l.is_empty() => Some(l.to_string()),
+            Some(_) => None,
+            None if self.options.guess_schema_locations => {
+                let last_n = n.rsplit(':').next().unwrap();
+                Some(format!("{}.xsd", last_n))
             }
+            None => None,
         }
-        _SerializerData::Struct { contents, .. } => {
-            for (tag, d) in contents {
-                if *tag == "$valueRaw" {
-                    let old_val = state.raw_output;
-                    state.raw_output = true;
-                    format_data(writer, d, state)?;
-                    state.raw_output = old_val;
-                } else if tag.starts_with(&"$value") {
-                    format_data(writer, d, state)?;
-                } else {
-                    let parsed_tag = Tag::from_cow(&tag);
-                    let base_name = parsed_tag.e;
-                    let name = match parsed_tag.p {
-                        Some(p) => format!("{}:{}", p, base_name),
-                        None => base_name.to_string(),
-                    };
-
-                    match d {
-                        _SerializerData::Seq(s) => {
-                            for d in s {
-                                let attrs = match d {
-                                    _SerializerData::Struct { attrs, .. } => attrs.to_owned(),
-                                    _ => vec![],
-                                };
-                                let attrs = attrs
-                                    .iter()
-                                    .map(|(attr_k, attr_v)| {
-                                        (xml::name::Name::from(Tag::from_cow(attr_k)), attr_v)
-                                    })
-                                    .collect::<Vec<_>>();
-                                let mut elm = xml::writer::XmlEvent::start_element(name.as_str());
-                                if state.include_schema_location {
-                                    elm =
-                                        elm.ns("xsi", "http://www.w3.org/2001/XMLSchema-instance");
-                                }
-                                let mut loc = String::new();
-                                let mut should_pop = false;
-                                if let Some(n) = parsed_tag.n {
-                                    match parsed_tag.p {
-                                        Some(p) => elm = elm.ns(p, n),
-                                        None => elm = elm.default_ns(n),
-                                    };
-                                    if !state.ns_stack.iter().any(|ns| ns == n) {
-                                        if let Some(l) = parsed_tag.l {
-                                            if !l.is_empty() {
-                                                loc.push_str(&format!("{} {}", n, l));
-                                            }
-                                        } else {
-                                            let last_n = n.rsplit(':').next().unwrap();
-                                            loc.push_str(&format!("{} {}.xsd", n, last_n));
-                                        }
-                                        if state.include_schema_location && !loc.is_empty() {
-                                            elm = elm.attr(
-                                                xml::name::Name {
-                                                    namespace: None,
-                                                    local_name: "schemaLocation",
-                                                    prefix: Some("xsi"),
-                                                },
-                                                &loc,
-                                            );
-                                        }
-                                        state.ns_stack.push(n.to_string());
-                                        should_pop = true;
-                                    }
-                                }
-                                for (name, attr_v) in attrs.clone() {
-                                    elm = elm.attr(name, attr_v);
-                                }
-
-                                writer.write(elm)?;
-                                format_data(writer, d, state)?;
-                                writer.write(
-                                    xml::writer::XmlEvent::end_element().name(name.as_str()),
-                                )?;
-
-                                if should_pop {
-                                    state.ns_stack.pop();
-                                }
-                            }
-                        }
-                        d => {
-                            let attrs = match d {
-                                _SerializerData::Struct { attrs, .. } => attrs.to_owned(),
-                                _ => vec![],
-                            };
-                            let attrs = attrs
-                                .iter()
-                                .map(|(attr_k, attr_v)| {
-                                    (xml::name::Name::from(Tag::from_cow(attr_k)), attr_v)
-                                })
-                                .collect::<Vec<_>>();
-
-                            let mut elm = xml::writer::XmlEvent::start_element(name.as_str());
-                            if state.include_schema_location {
-                                elm = elm.ns("xsi", "http://www.w3.org/2001/XMLSchema-instance");
-                            }
-
-                            let mut loc = String::new();
-                            let mut should_pop = false;
-                            if let Some(n) = parsed_tag.n {
-                                match parsed_tag.p {
-                                    Some(p) => elm = elm.ns(p, n),
-                                    None => elm = elm.default_ns(n),
-                                };
-                                if !state.ns_stack.iter().any(|ns| ns == n) {
-                                    if let Some(l) = parsed_tag.l {
-                                        if !l.is_empty() {
-                                            loc.push_str(&format!("{} {}", n, l));
-                                        }
-                                    } else {
-                                        let last_n = n.rsplit(':').next().unwrap();
-                                        loc.push_str(&format!("{} {}.xsd", n, last_n));
-                                    }
-                                    if state.include_schema_location && !loc.is_empty() {
-                                        elm = elm.attr(
-                                            xml::name::Name {
-                                                namespace: None,
-                                                local_name: "schemaLocation",
-                                                prefix: Some("xsi"),
-                                            },
-                                            &loc,
-                                        );
-                                    }
-                                    state.ns_stack.push(n.to_string());
-                                    should_pop = true;
-                                }
-                            }
-                            for (name, attr_v) in attrs {
-                                elm = elm.attr(name, attr_v);
-                            }
-
-                            writer.write(elm)?;
-                            format_data(writer, d, state)?;
-                            writer
-                                .write(xml::writer::XmlEvent::end_element().name(name.as_str()))?;
-
-                            if should_pop {
-                                state.ns_stack.pop();
-                            }
-                        }
-                    };
+    }
+
+    fn in_scope(&self, prefix: &str) -> Option<&str> {
+        self.ns_scope
+            .iter()
+            .rev()
+            .find(|(p, _)| p == prefix)
+            .map(|(_, n)| n.as_str())
+    }
+
+    /// A prefix to bind a namespace to where it cannot be the default namespace: that of an
+    /// unprefixed `xsi:type`, which would change the namespace of the element itself, or that of
+    /// an element whose `xsi:type` is in no namespace
+    fn type_prefix(&self, namespace: &str, bindings: &[(String, String)]) -> String {
+        if let Some((prefix, _)) = bindings
+            .iter()
+            .find(|(p, n)| !p.is_empty() && n == namespace)
+        {
+            return prefix.clone();
+        }
+        (1..)
+            .map(|i| format!("ns{}", i))
+            .find(|prefix| {
+                !bindings.iter().any(|(p, _)| p == prefix)
+                    && match self.in_scope(prefix) {
+                        Some(n) => n == namespace,
+                        None => true,
+                    }
+            })
+            .unwrap()
+    }
+
+    fn start_element(
+        &mut self,
+        parsed_tag: Tag,
+        attrs: &[(&'static str, String)],
+    ) -> Result<(), crate::Error> {
+        let mut bindings = vec![];
+        if self.elements.is_empty() {
+            bindings.extend(self.hoisted.iter().cloned());
+        }
+        let xsi_type = attrs
+            .iter()
+            .find(|(k, _)| *k == XSI_TYPE)
+            .map(|(_, v)| Tag::new(v));
+        if self.options.include_schema_location
+            || attrs.iter().any(|(k, _)| *k == XSI_NIL || *k == XSI_TYPE)
+        {
+            bindings.push(("xsi".to_string(), XSI_NS.to_string()));
+        }
+        // An unprefixed type name is read in the default namespace, which has to be unset for a
+        // type in none, so the element cannot be in the default namespace itself
+        let untyped_ns = matches!(xsi_type, Some(Tag { n: None, p: None, .. }));
+        let prefix = match (parsed_tag.p, parsed_tag.n) {
+            (Some(p), _) => Some(p.to_string()),
+            (None, Some(n)) if untyped_ns => Some(self.type_prefix(n, &bindings)),
+            (None, _) => None,
+        };
+        if let Some(n) = parsed_tag.n {
+            bindings.push((prefix.clone().unwrap_or_default(), n.to_string()));
+        }
+        if self.options.namespaces != Namespaces::PerElement {
+            for (attr_k, _) in attrs {
+                if let Tag {
+                    p: Some(p),
+                    n: Some(n),
+                    ..
+                } = Tag::from_static(attr_k)
+                {
+                    bindings.push((p.to_string(), n.to_string()));
+                }
+            }
+        }
+        let type_prefix = match xsi_type {
+            Some(Tag { n: Some(n), p, .. }) => {
+                let prefix = match p {
+                    Some(p) => p.to_string(),
+                    // The element's own default namespace can serve for the type's
+                    None if prefix.is_none() && parsed_tag.n == Some(n) => String::new(),
+                    None => self.type_prefix(n, &bindings),
+                };
+                bindings.push((prefix.clone(), n.to_string()));
+                Some(prefix)
+            }
+            _ => None,
+        };
+        let name = match &prefix {
+            Some(p) => format!("{}:{}", p, parsed_tag.e),
+            None => parsed_tag.e.to_string(),
+        };
+        let inherited_ns = self.elements.last().map_or("", |e| e.default_ns.as_str());
+        if untyped_ns {
+            // The writer will not unset a default namespace with `xmlns=""`
+            if !inherited_ns.is_empty() {
+                return Err(crate::Error::Message(format!(
+                    "xsi:type of element {} is in no namespace, but the default namespace there is {}",
+                    name, inherited_ns
+                )));
+            }
+            // Elements that would have used a default namespace hoisted here declare it themselves
+            bindings.retain(|(p, _)| !p.is_empty());
+        }
+        let mut elm = xml::writer::XmlEvent::start_element(name.as_str());
+        let default_ns = match bindings.iter().rev().find(|(p, _)| p.is_empty()) {
+            Some((_, n)) => n.clone(),
+            None => inherited_ns.to_string(),
+        };
+        let mut num_bindings = 0;
+        for (prefix, uri) in bindings {
+            if self.options.namespaces != Namespaces::PerElement {
+                if self.in_scope(&prefix) == Some(uri.as_str()) {
+                    continue;
+                }
+                self.ns_scope.push((prefix.clone(), uri.clone()));
+                num_bindings += 1;
+            }
+            elm = elm.ns(prefix, uri);
+        }
+
+        let mut loc = None;
+        let mut should_pop = false;
+        if let Some(n) = parsed_tag.n {
+            if !self.ns_stack.iter().any(|ns| ns == n) {
+                if let Some(l) = self.schema_location(&parsed_tag) {
+                    loc = Some(format!("{} {}", n, l));
+                    if !self.locations.iter().any(|(ns, _)| ns == n) {
+                        self.locations.push((n.to_string(), l));
+                    }
                 }
+                self.ns_stack.push(n.to_string());
+                should_pop = true;
+            }
+        }
+        let is_root = self.elements.is_empty();
+        if self.options.root_schema_location {
+            loc = match is_root && !self.root_locations.is_empty() {
+                true => Some(
+                    self.root_locations
+                        .iter()
+                        .map(|(n, l)| format!("{} {}", n, l))
+                        .collect::<Vec<_>>()
+                        .join(" "),
+                ),
+                false => None,
+            };
+        }
+        let no_ns_loc = match is_root {
+            true => self.options.no_namespace_schema_location.as_deref(),
+            false => None,
+        };
+        let loc = loc.map(|l| self.escape(&l, true).into_owned());
+        let no_ns_loc = no_ns_loc.map(|l| self.escape(l, true));
+        if self.options.include_schema_location {
+            if let Some(loc) = &loc {
+                elm = elm.attr(
+                    xml::name::Name {
+                        namespace: None,
+                        local_name: "schemaLocation",
+                        prefix: Some("xsi"),
+                    },
+                    loc,
+                );
+            }
+            if let Some(no_ns_loc) = &no_ns_loc {
+                elm = elm.attr(
+                    xml::name::Name {
+                        namespace: None,
+                        local_name: "noNamespaceSchemaLocation",
+                        prefix: Some("xsi"),
+                    },
+                    no_ns_loc,
+                );
+            }
+        }
+        let xsi_type = xsi_type.map(|t| match type_prefix.as_deref() {
+            Some(p) if !p.is_empty() => format!("{}:{}", p, t.e),
+            _ => t.e.to_string(),
+        });
+        let attrs = attrs
+            .iter()
+            .map(|(attr_k, attr_v)| match (*attr_k, &xsi_type) {
+                (XSI_TYPE, Some(xsi_type)) => (*attr_k, Cow::Borrowed(xsi_type.as_str())),
+                _ => (*attr_k, self.escape(attr_v, true)),
+            })
+            .collect::<Vec<_>>();
+        for (attr_k, attr_v) in &attrs {
+            elm = elm.attr(xml::name::Name::from(Tag::from_static(attr_k)), attr_v);
+        }
+
+        let preserve = match attrs.iter().find(|(attr_k, _)| {
+            let tag = Tag::from_static(attr_k);
+            tag.p == Some("xml") && tag.e == "space"
+        }) {
+            Some((_, space)) => space == "preserve",
+            None => self.elements.last().is_some_and(|e| e.preserve),
+        };
+
+        self.writer.write(elm.into())?;
+        self.elements.push(OpenElement {
+            name,
+            pop_ns: should_pop,
+            bindings: num_bindings,
+            default_ns,
+            preserve,
+        });
+        self.is_empty = true;
+        self.keep_unindented()?;
+        Ok(())
+    }
+
+    fn end_element(&mut self) -> Result<(), crate::Error> {
+        let element = self.elements.pop().expect("no element to end");
+        if self.is_empty && self.options.self_closing == SelfClosing::Expanded {
+            self.writer.write(xml::writer::XmlEvent::characters(""))?;
+        }
+        self.is_empty = false;
+        self.writer.write(
+            xml::writer::XmlEvent::end_element()
+                .name(element.name.as_str())
+                .into(),
+        )?;
+        if element.pop_ns {
+            self.ns_stack.pop();
+        }
+        self.ns_scope
+            .truncate(self.ns_scope.len() - element.bindings);
+        self.keep_unindented()
+    }
+
+    /// Stop the emitter indenting the next tag inside an element with `xml:space="preserve"`
+    ///
+    /// The emitter leaves alone elements it has written text into, so an empty run of text does.
+    fn keep_unindented(&mut self) -> Result<(), crate::Error> {
+        if self.options.perform_indent && self.elements.last().is_some_and(|e| e.preserve) {
+            self.writer.write(xml::writer::XmlEvent::characters(""))?;
+        }
+        Ok(())
+    }
+
+    /// Escape text as the options ask, unless the writer is going to escape it anyway
+    fn escape<'s>(&self, s: &'s str, attr: bool) -> Cow<'s, str> {
+        match self.writer_escapes {
+            true => Cow::Borrowed(s),
+            false => self.options.escaping.escape(s, attr, &self.charset),
+        }
+    }
+
+    fn write_text(&mut self, s: &str, cdata: bool) -> Result<(), crate::Error> {
+        // Writing nothing leaves an element without other content free to close itself
+        if s.is_empty() {
+            return Ok(());
+        }
+        self.is_empty = false;
+        if self.raw_output && self.writer_escapes {
+            self.writer.write_raw(s)?;
+            return Ok(());
+        }
+        let s = match self.raw_output {
+            true => Cow::Borrowed(s),
+            false => self.escape(s, false),
+        };
+        match cdata && !self.writer_escapes {
+            true => self.writer.write(xml::writer::XmlEvent::cdata(&s))?,
+            false => self.writer.write(xml::writer::XmlEvent::characters(&s))?,
+        }
+        Ok(())
+    }
+}
+
+/// Format a float in the lexical space of XSD `float` and `double`, writing the special values as
+/// `INF`, `-INF` and `NaN`; `display` is the value at its original precision
+fn format_float(v: f64, display: impl std::fmt::Display) -> String {
+    if v.is_nan() {
+        "NaN".to_string()
+    } else if v.is_infinite() {
+        match v.is_sign_positive() {
+            true => "INF".to_string(),
+            false => "-INF".to_string(),
+        }
+    } else {
+        display.to_string()
+    }
+}
+
+/// Where a value is being written relative to the element tree
+enum Mode {
+    /// Inline, as the content of the current element
+    Content,
+    /// Wrapped in a new element with the given tag; `in_seq` is set for the items of a
+    /// sequence, which are not repeated again
+    Element {
+        tag: Cow<'static, str>,
+        in_seq: bool,
+    },
+}
+
+struct ValueSerializer<'a, 'w> {
+    ser: &'a mut Serializer<'w>,
+    mode: Mode,
+    attrs: Attrs,
+}
+
+impl<'a, 'w> ValueSerializer<'a, 'w> {
+    fn write_text(self, s: &str, cdata: bool) -> Result<(), crate::Error> {
+        match self.mode {
+            Mode::Content => self.ser.write_text(s, cdata),
+            Mode::Element { tag, .. } => {
+                self.ser.start_element(Tag::from_cow(&tag), &self.attrs)?;
+                self.ser.write_text(s, cdata)?;
+                self.ser.end_element()
             }
         }
     }
-    Ok(())
+
+    /// Open the elements for a compound value; the attributes go on the variant element of
+    /// struct variants, and on the value's own element otherwise
+    fn open(self, variant: Option<&'static str>) -> Result<Compound<'a, 'w>, crate::Error> {
+        let mut close = 0;
+        if let Mode::Element { tag, .. } = &self.mode {
+            let tag_attrs = match variant {
+                Some(_) => &[][..],
+                None => &self.attrs[..],
+            };
+            self.ser.start_element(Tag::from_cow(tag), tag_attrs)?;
+            close += 1;
+        }
+        if let Some(variant) = variant {
+            self.ser
+                .start_element(Tag::from_static(variant), &self.attrs)?;
+            close += 1;
+        }
+        Ok(Compound {
+            ser: self.ser,
+            repeat: None,
+            key: None,
+            close,
+        })
+    }
 }
 
-impl<'a> ser::Serializer for &'a mut Serializer {
-    type Ok = _SerializerData;
+impl<'a, 'w> ser::Serializer for ValueSerializer<'a, 'w> {
+    type Ok = ();
     type Error = crate::Error;
-    type SerializeSeq = SeqSerializer<'a>;
-    type SerializeTuple = SeqSerializer<'a>;
-    type SerializeTupleStruct = SeqSerializer<'a>;
-    type SerializeTupleVariant = SeqSerializer<'a>;
-    type SerializeMap = MapSerializer<'a>;
-    type SerializeStruct = StructSerializer<'a>;
-    type SerializeStructVariant = StructVariantSerializer<'a>;
-
-    fn serialize_bool(self, v: bool) -> Result<_SerializerData, Self::Error> {
+    type SerializeSeq = Compound<'a, 'w>;
+    type SerializeTuple = Compound<'a, 'w>;
+    type SerializeTupleStruct = Compound<'a, 'w>;
+    type SerializeTupleVariant = Compound<'a, 'w>;
+    type SerializeMap = Compound<'a, 'w>;
+    type SerializeStruct = Compound<'a, 'w>;
+    type SerializeStructVariant = Compound<'a, 'w>;
+
+    fn serialize_bool(self, v: bool) -> Result<(), Self::Error> {
         let val = if v { "true" } else { "false" };
-        Ok(_SerializerData::String(val.to_string()))
+        self.write_text(val, false)
     }
 
-    fn serialize_i8(self, v: i8) -> Result<_SerializerData, Self::Error> {
+    fn serialize_i8(self, v: i8) -> Result<(), Self::Error> {
         self.serialize_i64(i64::from(v))
     }
 
-    fn serialize_i16(self, v: i16) -> Result<_SerializerData, Self::Error> {
+    fn serialize_i16(self, v: i16) -> Result<(), Self::Error> {
         self.serialize_i64(i64::from(v))
     }
 
-    fn serialize_i32(self, v: i32) -> Result<_SerializerData, Self::Error> {
+    fn serialize_i32(self, v: i32) -> Result<(), Self::Error> {
         self.serialize_i64(i64::from(v))
     }
 
-    fn serialize_i64(self, v: i64) -> Result<_SerializerData, Self::Error> {
-        Ok(_SerializerData::String(v.to_string()))
+    fn serialize_i64(self, v: i64) -> Result<(), Self::Error> {
+        self.write_text(&v.to_string(), false)
     }
 
-    fn serialize_u8(self, v: u8) -> Result<_SerializerData, Self::Error> {
+    fn serialize_u8(self, v: u8) -> Result<(), Self::Error> {
         self.serialize_u64(u64::from(v))
     }
 
-    fn serialize_u16(self, v: u16) -> Result<_SerializerData, Self::Error> {
+    fn serialize_u16(self, v: u16) -> Result<(), Self::Error> {
         self.serialize_u64(u64::from(v))
     }
 
-    fn serialize_u32(self, v: u32) -> Result<_SerializerData, Self::Error> {
+    fn serialize_u32(self, v: u32) -> Result<(), Self::Error> {
         self.serialize_u64(u64::from(v))
     }
 
-    fn serialize_u64(self, v: u64) -> Result<_SerializerData, Self::Error> {
-        Ok(_SerializerData::String(v.to_string()))
+    fn serialize_u64(self, v: u64) -> Result<(), Self::Error> {
+        self.write_text(&v.to_string(), false)
     }
 
-    fn serialize_f32(self, v: f32) -> Result<_SerializerData, Self::Error> {
-        self.serialize_f64(f64::from(v))
+    fn serialize_f32(self, v: f32) -> Result<(), Self::Error> {
+        self.write_text(&format_float(f64::from(v), v), false)
     }
 
-    fn serialize_f64(self, v: f64) -> Result<_SerializerData, Self::Error> {
-        Ok(_SerializerData::String(v.to_string()))
+    fn serialize_f64(self, v: f64) -> Result<(), Self::Error> {
+        self.write_text(&format_float(v, v), false)
     }
 
-    fn serialize_char(self, v: char) -> Result<_SerializerData, Self::Error> {
+    fn serialize_char(self, v: char) -> Result<(), Self::Error> {
         self.serialize_str(&v.to_string())
     }
 
-    fn serialize_str(self, v: &str) -> Result<_SerializerData, Self::Error> {
-        Ok(_SerializerData::CData(v.to_string()))
+    fn serialize_str(self, v: &str) -> Result<(), Self::Error> {
+        self.write_text(v, true)
     }
 
-    fn serialize_bytes(self, v: &[u8]) -> Result<_SerializerData, Self::Error> {
-        Ok(_SerializerData::String(hex::encode(v)))
+    fn serialize_bytes(self, v: &[u8]) -> Result<(), Self::Error> {
+        let s = self.ser.binary.encode(v);
+        self.write_text(&s, false)
     }
 
-    fn serialize_none(self) -> Result<_SerializerData, Self::Error> {
-        Ok(_SerializerData::String("".to_string()))
+    fn serialize_none(self) -> Result<(), Self::Error> {
+        match (self.mode, self.ser.options.none_elements) {
+            (Mode::Element { .. }, NoneElements::Omit) => Ok(()),
+            (Mode::Element { tag, .. }, NoneElements::Nil) => {
+                self.ser
+                    .start_element(Tag::from_cow(&tag), &[(XSI_NIL, "true".to_string())])?;
+                self.ser.end_element()
+            }
+            (mode, _) => ValueSerializer { mode, ..self }.write_text("", false),
+        }
     }
 
-    fn serialize_some<T>(self, value: &T) -> Result<_SerializerData, Self::Error>
+    fn serialize_some<T>(self, value: &T) -> Result<(), Self::Error>
     where
         T: ?Sized + Serialize,
     {
         value.serialize(self)
     }
 
-    fn serialize_unit(self) -> Result<_SerializerData, Self::Error> {
-        self.serialize_none()
+    fn serialize_unit(self) -> Result<(), Self::Error> {
+        self.write_text("", false)
     }
 
-    fn serialize_unit_struct(self, _name: &'static str) -> Result<_SerializerData, Self::Error> {
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<(), Self::Error> {
         self.serialize_unit()
     }
 
@@ -460,18 +1269,28 @@ impl<'a> ser::Serializer for &'a mut Serializer {
         _name: &'static str,
         _variant_index: u32,
         variant: &'static str,
-    ) -> Result<_SerializerData, Self::Error> {
+    ) -> Result<(), Self::Error> {
+        if variant.starts_with("$type:") {
+            return self.open(None)?.close();
+        }
         self.serialize_str(variant)
     }
 
-    fn serialize_newtype_struct<T>(
-        self,
-        _name: &'static str,
-        value: &T,
-    ) -> Result<_SerializerData, Self::Error>
+    fn serialize_newtype_struct<T>(self, name: &'static str, value: &T) -> Result<(), Self::Error>
     where
         T: ?Sized + Serialize,
     {
+        if name == crate::raw::RAW_XML {
+            // The markup brings its own element, in place of the one the value would be wrapped in
+            let old_val = std::mem::replace(&mut self.ser.raw_output, true);
+            let res = value.serialize(ValueSerializer {
+                ser: &mut *self.ser,
+                mode: Mode::Content,
+                attrs: vec![],
+            });
+            self.ser.raw_output = old_val;
+            return res;
+        }
         value.serialize(self)
     }
 
@@ -481,22 +1300,28 @@ impl<'a> ser::Serializer for &'a mut Serializer {
         _variant_index: u32,
         variant: &'static str,
         value: &T,
-    ) -> Result<_SerializerData, Self::Error>
+    ) -> Result<(), Self::Error>
     where
         T: ?Sized + Serialize,
     {
-        let value = value.serialize(&mut *self)?;
-        Ok(_SerializerData::Struct {
-            attrs: vec![],
-            contents: vec![(variant.into(), value)],
-        })
+        if variant.starts_with("$type:") || variant == "$text" {
+            return value.serialize(self);
+        }
+        let compound = self.open(None)?;
+        compound.ser.serialize_field(variant.into(), value)?;
+        compound.close()
     }
 
     fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq, Self::Error> {
-        Ok(SeqSerializer {
-            parent: self,
-            output: vec![],
-        })
+        match self.mode {
+            Mode::Element { tag, in_seq: false } => Ok(Compound {
+                ser: self.ser,
+                repeat: Some(tag),
+                key: None,
+                close: 0,
+            }),
+            _ => self.open(None),
+        }
     }
 
     fn serialize_tuple(self, len: usize) -> Result<Self::SerializeTuple, Self::Error> {
@@ -522,11 +1347,7 @@ impl<'a> ser::Serializer for &'a mut Serializer {
     }
 
     fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap, Self::Error> {
-        Ok(MapSerializer {
-            parent: self,
-            keys: vec![],
-            cur_key: String::new(),
-        })
+        self.open(None)
     }
 
     fn serialize_struct(
@@ -534,11 +1355,7 @@ impl<'a> ser::Serializer for &'a mut Serializer {
         _name: &'static str,
         _len: usize,
     ) -> Result<Self::SerializeStruct, Self::Error> {
-        Ok(StructSerializer {
-            parent: self,
-            attrs: vec![],
-            keys: vec![],
-        })
+        self.open(None)
     }
 
     fn serialize_struct_variant(
@@ -548,108 +1365,135 @@ impl<'a> ser::Serializer for &'a mut Serializer {
         variant: &'static str,
         _len: usize,
     ) -> Result<Self::SerializeStructVariant, Self::Error> {
-        Ok(StructVariantSerializer {
-            parent: self,
-            attrs: vec![],
-            keys: vec![],
-            tag: variant.to_string(),
-        })
+        match variant.starts_with("$type:") {
+            true => self.open(None),
+            false => self.open(Some(variant)),
+        }
     }
 }
 
-pub struct SeqSerializer<'a> {
-    parent: &'a mut Serializer,
-    output: Vec<_SerializerData>,
+/// Writer for the contents of compound values
+pub struct Compound<'a, 'w> {
+    ser: &'a mut Serializer<'w>,
+    /// Tag to wrap each sequence item in
+    repeat: Option<Cow<'static, str>>,
+    /// Pending map key
+    key: Option<String>,
+    /// Number of elements to close once the value is done
+    close: usize,
 }
 
-impl<'a> ser::SerializeSeq for SeqSerializer<'a> {
-    type Ok = _SerializerData;
-    type Error = crate::Error;
+impl<'a, 'w> Compound<'a, 'w> {
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), crate::Error> {
+        match &self.repeat {
+            Some(tag) => {
+                let attrs = value.serialize(AttrCollector(self.ser.options.binary_encoding))?;
+                value.serialize(ValueSerializer {
+                    ser: self.ser,
+                    mode: Mode::Element {
+                        tag: tag.clone(),
+                        in_seq: true,
+                    },
+                    attrs,
+                })
+            }
+            None => self.ser.serialize(value),
+        }
+    }
 
-    fn serialize_element<T>(&mut self, value: &T) -> Result<(), Self::Error>
-    where
-        T: ?Sized + Serialize,
-    {
-        let val = value.serialize(&mut *self.parent)?;
-        self.output.push(val);
-        Ok(())
+    fn serialize_field<T: ?Sized + Serialize>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), crate::Error> {
+        // Attributes were already written when the element was opened
+        if BinaryEncoding::split_tag(key).1.starts_with("$attr:") {
+            return Ok(());
+        }
+        self.ser.serialize_field(key.into(), value)
     }
 
-    fn end(self) -> Result<_SerializerData, Self::Error> {
-        Ok(_SerializerData::Seq(self.output))
+    fn close(self) -> Result<(), crate::Error> {
+        for _ in 0..self.close {
+            self.ser.end_element()?;
+        }
+        Ok(())
     }
 }
 
-impl<'a> ser::SerializeTuple for SeqSerializer<'a> {
-    type Ok = _SerializerData;
+impl<'a, 'w> ser::SerializeSeq for Compound<'a, 'w> {
+    type Ok = ();
     type Error = crate::Error;
 
     fn serialize_element<T>(&mut self, value: &T) -> Result<(), Self::Error>
     where
         T: ?Sized + Serialize,
     {
-        let val = value.serialize(&mut *self.parent)?;
-        self.output.push(val);
-        Ok(())
+        Compound::serialize_element(self, value)
     }
 
-    fn end(self) -> Result<_SerializerData, Self::Error> {
-        Ok(_SerializerData::Seq(self.output))
+    fn end(self) -> Result<(), Self::Error> {
+        self.close()
     }
 }
 
-impl<'a> ser::SerializeTupleStruct for SeqSerializer<'a> {
-    type Ok = _SerializerData;
+impl<'a, 'w> ser::SerializeTuple for Compound<'a, 'w> {
+    type Ok = ();
     type Error = crate::Error;
 
-    fn serialize_field<T>(&mut self, value: &T) -> Result<(), Self::Error>
+    fn serialize_element<T>(&mut self, value: &T) -> Result<(), Self::Error>
     where
         T: ?Sized + Serialize,
     {
-        let val = value.serialize(&mut *self.parent)?;
-        self.output.push(val);
-        Ok(())
+        Compound::serialize_element(self, value)
     }
 
-    fn end(self) -> Result<_SerializerData, Self::Error> {
-        Ok(_SerializerData::Seq(self.output))
+    fn end(self) -> Result<(), Self::Error> {
+        self.close()
     }
 }
 
-impl<'a> ser::SerializeTupleVariant for SeqSerializer<'a> {
-    type Ok = _SerializerData;
+impl<'a, 'w> ser::SerializeTupleStruct for Compound<'a, 'w> {
+    type Ok = ();
     type Error = crate::Error;
 
     fn serialize_field<T>(&mut self, value: &T) -> Result<(), Self::Error>
     where
         T: ?Sized + Serialize,
     {
-        let val = value.serialize(&mut *self.parent)?;
-        self.output.push(val);
-        Ok(())
+        Compound::serialize_element(self, value)
     }
 
-    fn end(self) -> Result<_SerializerData, Self::Error> {
-        Ok(_SerializerData::Seq(self.output))
+    fn end(self) -> Result<(), Self::Error> {
+        self.close()
     }
 }
 
-pub struct MapSerializer<'a> {
-    parent: &'a mut Serializer,
-    keys: Vec<(Cow<'static, str>, _SerializerData)>,
-    cur_key: String,
+impl<'a, 'w> ser::SerializeTupleVariant for Compound<'a, 'w> {
+    type Ok = ();
+    type Error = crate::Error;
+
+    fn serialize_field<T>(&mut self, value: &T) -> Result<(), Self::Error>
+    where
+        T: ?Sized + Serialize,
+    {
+        Compound::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<(), Self::Error> {
+        self.close()
+    }
 }
 
-impl<'a> ser::SerializeMap for MapSerializer<'a> {
-    type Ok = _SerializerData;
+impl<'a, 'w> ser::SerializeMap for Compound<'a, 'w> {
+    type Ok = ();
     type Error = crate::Error;
 
     fn serialize_key<T>(&mut self, key: &T) -> Result<(), Self::Error>
     where
         T: ?Sized + Serialize,
     {
-        let val = key.serialize(&mut *self.parent)?;
-        self.cur_key = val.as_str();
+        self.key = Some(key.serialize(StringSerializer(self.ser.options.binary_encoding))?);
         Ok(())
     }
 
@@ -657,92 +1501,1811 @@ impl<'a> ser::SerializeMap for MapSerializer<'a> {
     where
         T: ?Sized + Serialize,
     {
-        let val = value.serialize(&mut *self.parent)?;
-        self.keys.push((self.cur_key.clone().into(), val));
-        Ok(())
+        let key = self.key.take().unwrap_or_default();
+        self.ser.serialize_field(key.into(), value)
     }
 
-    fn end(self) -> Result<_SerializerData, Self::Error> {
-        Ok(_SerializerData::Struct {
-            attrs: vec![],
-            contents: self
-                .keys
-                .into_iter()
-                .map(|(k, v)| (Cow::from(k), v))
-                .collect(),
-        })
+    fn end(self) -> Result<(), Self::Error> {
+        self.close()
     }
 }
 
-pub struct StructSerializer<'a> {
-    parent: &'a mut Serializer,
-    attrs: Vec<(&'static str, String)>,
-    keys: Vec<(&'static str, _SerializerData)>,
+impl<'a, 'w> ser::SerializeStruct for Compound<'a, 'w> {
+    type Ok = ();
+    type Error = crate::Error;
+
+    fn serialize_field<T>(&mut self, key: &'static str, value: &T) -> Result<(), Self::Error>
+    where
+        T: ?Sized + Serialize,
+    {
+        Compound::serialize_field(self, key, value)
+    }
+
+    fn end(self) -> Result<(), Self::Error> {
+        self.close()
+    }
 }
 
-impl<'a> ser::SerializeStruct for StructSerializer<'a> {
-    type Ok = _SerializerData;
+impl<'a, 'w> ser::SerializeStructVariant for Compound<'a, 'w> {
+    type Ok = ();
     type Error = crate::Error;
 
     fn serialize_field<T>(&mut self, key: &'static str, value: &T) -> Result<(), Self::Error>
     where
         T: ?Sized + Serialize,
     {
-        let val = value.serialize(&mut *self.parent)?;
+        Compound::serialize_field(self, key, value)
+    }
+
+    fn end(self) -> Result<(), Self::Error> {
+        self.close()
+    }
+}
+
+/// Collects the `$attr:` fields of a value, so they can be written on its start element before
+/// any of its children are streamed out
+struct AttrCollector(BinaryEncoding);
+
+impl ser::Serializer for AttrCollector {
+    type Ok = Attrs;
+    type Error = crate::Error;
+    type SerializeSeq = AttrCollectorCompound;
+    type SerializeTuple = AttrCollectorCompound;
+    type SerializeTupleStruct = AttrCollectorCompound;
+    type SerializeTupleVariant = AttrCollectorCompound;
+    type SerializeMap = AttrCollectorCompound;
+    type SerializeStruct = AttrCollectorCompound;
+    type SerializeStructVariant = AttrCollectorCompound;
+
+    fn serialize_bool(self, _v: bool) -> Result<Attrs, Self::Error> {
+        Ok(vec![])
+    }
+
+    fn serialize_i8(self, _v: i8) -> Result<Attrs, Self::Error> {
+        Ok(vec![])
+    }
+
+    fn serialize_i16(self, _v: i16) -> Result<Attrs, Self::Error> {
+        Ok(vec![])
+    }
+
+    fn serialize_i32(self, _v: i32) -> Result<Attrs, Self::Error> {
+        Ok(vec![])
+    }
+
+    fn serialize_i64(self, _v: i64) -> Result<Attrs, Self::Error> {
+        Ok(vec![])
+    }
+
+    fn serialize_u8(self, _v: u8) -> Result<Attrs, Self::Error> {
+        Ok(vec![])
+    }
+
+    fn serialize_u16(self, _v: u16) -> Result<Attrs, Self::Error> {
+        Ok(vec![])
+    }
+
+    fn serialize_u32(self, _v: u32) -> Result<Attrs, Self::Error> {
+        Ok(vec![])
+    }
+
+    fn serialize_u64(self, _v: u64) -> Result<Attrs, Self::Error> {
+        Ok(vec![])
+    }
+
+    fn serialize_f32(self, _v: f32) -> Result<Attrs, Self::Error> {
+        Ok(vec![])
+    }
+
+    fn serialize_f64(self, _v: f64) -> Result<Attrs, Self::Error> {
+        Ok(vec![])
+    }
+
+    fn serialize_char(self, _v: char) -> Result<Attrs, Self::Error> {
+        Ok(vec![])
+    }
+
+    fn serialize_str(self, _v: &str) -> Result<Attrs, Self::Error> {
+        Ok(vec![])
+    }
+
+    fn serialize_bytes(self, _v: &[u8]) -> Result<Attrs, Self::Error> {
+        Ok(vec![])
+    }
+
+    fn serialize_none(self) -> Result<Attrs, Self::Error> {
+        Ok(vec![])
+    }
+
+    fn serialize_some<T>(self, value: &T) -> Result<Attrs, Self::Error>
+    where
+        T: ?Sized + Serialize,
+    {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Attrs, Self::Error> {
+        Ok(vec![])
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Attrs, Self::Error> {
+        Ok(vec![])
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<Attrs, Self::Error> {
+        Ok(xsi_type(variant).into_iter().collect())
+    }
+
+    fn serialize_newtype_struct<T>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<Attrs, Self::Error>
+    where
+        T: ?Sized + Serialize,
+    {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<Attrs, Self::Error>
+    where
+        T: ?Sized + Serialize,
+    {
+        match xsi_type(variant) {
+            Some(attr) => {
+                let mut attrs = value.serialize(self)?;
+                attrs.push(attr);
+                Ok(attrs)
+            }
+            None => Ok(vec![]),
+        }
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq, Self::Error> {
+        Ok(AttrCollectorCompound(vec![], self.0))
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple, Self::Error> {
+        Ok(AttrCollectorCompound(vec![], self.0))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleStruct, Self::Error> {
+        Ok(AttrCollectorCompound(vec![], self.0))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleVariant, Self::Error> {
+        Ok(AttrCollectorCompound(vec![], self.0))
+    }
 
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap, Self::Error> {
+        Ok(AttrCollectorCompound(vec![], self.0))
+    }
+
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStruct, Self::Error> {
+        Ok(AttrCollectorCompound(vec![], self.0))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStructVariant, Self::Error> {
+        Ok(AttrCollectorCompound(
+            xsi_type(variant).into_iter().collect(),
+            self.0,
+        ))
+    }
+}
+
+/// The `xsi:type` attribute for a `$type:` enum variant
+fn xsi_type(variant: &'static str) -> Option<(&'static str, String)> {
+    let type_tag = variant.strip_prefix("$type:")?;
+    Some((XSI_TYPE, type_tag.to_string()))
+}
+
+/// Attributes found so far, and the default encoding for byte strings
+struct AttrCollectorCompound(Attrs, BinaryEncoding);
+
+impl AttrCollectorCompound {
+    fn serialize_field<T: ?Sized + Serialize>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), crate::Error> {
+        let (binary, key) = BinaryEncoding::split_tag(key);
         if let Some(attr) = key.strip_prefix("$attr:") {
-            self.attrs.push((attr, val.as_str()));
-        } else {
-            self.keys.push((key, val));
+            let value = value.serialize(StringSerializer(binary.unwrap_or(self.1)))?;
+            self.0.push((attr, value));
         }
+        Ok(())
+    }
+}
+
+impl ser::SerializeSeq for AttrCollectorCompound {
+    type Ok = Attrs;
+    type Error = crate::Error;
 
+    fn serialize_element<T>(&mut self, _value: &T) -> Result<(), Self::Error>
+    where
+        T: ?Sized + Serialize,
+    {
         Ok(())
     }
 
-    fn end(self) -> Result<_SerializerData, Self::Error> {
-        Ok(_SerializerData::Struct {
-            attrs: self.attrs.into_iter().map(|(k, v)| (k.into(), v)).collect(),
-            contents: self.keys.into_iter().map(|(k, v)| (k.into(), v)).collect(),
-        })
+    fn end(self) -> Result<Attrs, Self::Error> {
+        Ok(self.0)
     }
 }
 
-pub struct StructVariantSerializer<'a> {
-    parent: &'a mut Serializer,
-    attrs: Vec<(Cow<'static, str>, String)>,
-    keys: Vec<(Cow<'static, str>, _SerializerData)>,
-    tag: String,
+impl ser::SerializeTuple for AttrCollectorCompound {
+    type Ok = Attrs;
+    type Error = crate::Error;
+
+    fn serialize_element<T>(&mut self, _value: &T) -> Result<(), Self::Error>
+    where
+        T: ?Sized + Serialize,
+    {
+        Ok(())
+    }
+
+    fn end(self) -> Result<Attrs, Self::Error> {
+        Ok(self.0)
+    }
 }
 
-impl<'a> ser::SerializeStructVariant for StructVariantSerializer<'a> {
-    type Ok = _SerializerData;
+impl ser::SerializeTupleStruct for AttrCollectorCompound {
+    type Ok = Attrs;
     type Error = crate::Error;
 
-    fn serialize_field<T>(&mut self, key: &'static str, value: &T) -> Result<(), Self::Error>
+    fn serialize_field<T>(&mut self, _value: &T) -> Result<(), Self::Error>
     where
         T: ?Sized + Serialize,
     {
-        let val = value.serialize(&mut *self.parent)?;
+        Ok(())
+    }
 
-        if let Some(attr) = key.strip_prefix("$attr:") {
-            self.attrs.push((attr.into(), val.as_str()));
-        } else {
-            self.keys.push((key.into(), val));
-        }
+    fn end(self) -> Result<Attrs, Self::Error> {
+        Ok(self.0)
+    }
+}
 
+impl ser::SerializeTupleVariant for AttrCollectorCompound {
+    type Ok = Attrs;
+    type Error = crate::Error;
+
+    fn serialize_field<T>(&mut self, _value: &T) -> Result<(), Self::Error>
+    where
+        T: ?Sized + Serialize,
+    {
         Ok(())
     }
 
-    fn end(self) -> Result<_SerializerData, Self::Error> {
-        Ok(_SerializerData::Struct {
-            attrs: vec![],
-            contents: vec![(
-                self.tag.into(),
-                _SerializerData::Struct {
-                    attrs: self.attrs,
-                    contents: self.keys,
-                },
-            )],
-        })
+    fn end(self) -> Result<Attrs, Self::Error> {
+        Ok(self.0)
+    }
+}
+
+impl ser::SerializeMap for AttrCollectorCompound {
+    type Ok = Attrs;
+    type Error = crate::Error;
+
+    fn serialize_key<T>(&mut self, _key: &T) -> Result<(), Self::Error>
+    where
+        T: ?Sized + Serialize,
+    {
+        Ok(())
+    }
+
+    fn serialize_value<T>(&mut self, _value: &T) -> Result<(), Self::Error>
+    where
+        T: ?Sized + Serialize,
+    {
+        Ok(())
+    }
+
+    fn end(self) -> Result<Attrs, Self::Error> {
+        Ok(self.0)
+    }
+}
+
+impl ser::SerializeStruct for AttrCollectorCompound {
+    type Ok = Attrs;
+    type Error = crate::Error;
+
+    fn serialize_field<T>(&mut self, key: &'static str, value: &T) -> Result<(), Self::Error>
+    where
+        T: ?Sized + Serialize,
+    {
+        AttrCollectorCompound::serialize_field(self, key, value)
+    }
+
+    fn end(self) -> Result<Attrs, Self::Error> {
+        Ok(self.0)
+    }
+}
+
+impl ser::SerializeStructVariant for AttrCollectorCompound {
+    type Ok = Attrs;
+    type Error = crate::Error;
+
+    fn serialize_field<T>(&mut self, key: &'static str, value: &T) -> Result<(), Self::Error>
+    where
+        T: ?Sized + Serialize,
+    {
+        AttrCollectorCompound::serialize_field(self, key, value)
+    }
+
+    fn end(self) -> Result<Attrs, Self::Error> {
+        Ok(self.0)
+    }
+}
+
+/// Flattens a value into a single string, for attribute values and map keys
+///
+/// Sequences and the fields of structs and maps are joined with commas.
+struct StringSerializer(BinaryEncoding);
+
+impl ser::Serializer for StringSerializer {
+    type Ok = String;
+    type Error = crate::Error;
+    type SerializeSeq = StringCompound;
+    type SerializeTuple = StringCompound;
+    type SerializeTupleStruct = StringCompound;
+    type SerializeTupleVariant = StringCompound;
+    type SerializeMap = StringCompound;
+    type SerializeStruct = StringCompound;
+    type SerializeStructVariant = StringCompound;
+
+    fn serialize_bool(self, v: bool) -> Result<String, Self::Error> {
+        let val = if v { "true" } else { "false" };
+        Ok(val.to_string())
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<String, Self::Error> {
+        self.serialize_i64(i64::from(v))
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<String, Self::Error> {
+        self.serialize_i64(i64::from(v))
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<String, Self::Error> {
+        self.serialize_i64(i64::from(v))
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<String, Self::Error> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<String, Self::Error> {
+        self.serialize_u64(u64::from(v))
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<String, Self::Error> {
+        self.serialize_u64(u64::from(v))
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<String, Self::Error> {
+        self.serialize_u64(u64::from(v))
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<String, Self::Error> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<String, Self::Error> {
+        Ok(format_float(f64::from(v), v))
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<String, Self::Error> {
+        Ok(format_float(v, v))
+    }
+
+    fn serialize_char(self, v: char) -> Result<String, Self::Error> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_str(self, v: &str) -> Result<String, Self::Error> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<String, Self::Error> {
+        Ok(self.0.encode(v))
+    }
+
+    fn serialize_none(self) -> Result<String, Self::Error> {
+        Ok(String::new())
+    }
+
+    fn serialize_some<T>(self, value: &T) -> Result<String, Self::Error>
+    where
+        T: ?Sized + Serialize,
+    {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<String, Self::Error> {
+        Ok(String::new())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<String, Self::Error> {
+        Ok(String::new())
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<String, Self::Error> {
+        Ok(variant.to_string())
+    }
+
+    fn serialize_newtype_struct<T>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<String, Self::Error>
+    where
+        T: ?Sized + Serialize,
+    {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        value: &T,
+    ) -> Result<String, Self::Error>
+    where
+        T: ?Sized + Serialize,
+    {
+        value.serialize(self)
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq, Self::Error> {
+        Ok(StringCompound(vec![], self.0))
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple, Self::Error> {
+        Ok(StringCompound(vec![], self.0))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleStruct, Self::Error> {
+        Ok(StringCompound(vec![], self.0))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleVariant, Self::Error> {
+        Ok(StringCompound(vec![], self.0))
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap, Self::Error> {
+        Ok(StringCompound(vec![], self.0))
+    }
+
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStruct, Self::Error> {
+        Ok(StringCompound(vec![], self.0))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStructVariant, Self::Error> {
+        Ok(StringCompound(vec![], self.0))
+    }
+}
+
+/// Parts of the string so far, and the encoding for byte strings
+struct StringCompound(Vec<String>, BinaryEncoding);
+
+impl StringCompound {
+    fn push<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), crate::Error> {
+        self.0.push(value.serialize(StringSerializer(self.1))?);
+        Ok(())
+    }
+
+    fn push_field<T: ?Sized + Serialize>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), crate::Error> {
+        let (binary, key) = BinaryEncoding::split_tag(key);
+        if key.starts_with("$attr:") {
+            return Ok(());
+        }
+        self.0
+            .push(value.serialize(StringSerializer(binary.unwrap_or(self.1)))?);
+        Ok(())
+    }
+}
+
+impl ser::SerializeSeq for StringCompound {
+    type Ok = String;
+    type Error = crate::Error;
+
+    fn serialize_element<T>(&mut self, value: &T) -> Result<(), Self::Error>
+    where
+        T: ?Sized + Serialize,
+    {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<String, Self::Error> {
+        Ok(self.0.join(","))
+    }
+}
+
+impl ser::SerializeTuple for StringCompound {
+    type Ok = String;
+    type Error = crate::Error;
+
+    fn serialize_element<T>(&mut self, value: &T) -> Result<(), Self::Error>
+    where
+        T: ?Sized + Serialize,
+    {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<String, Self::Error> {
+        Ok(self.0.join(","))
+    }
+}
+
+impl ser::SerializeTupleStruct for StringCompound {
+    type Ok = String;
+    type Error = crate::Error;
+
+    fn serialize_field<T>(&mut self, value: &T) -> Result<(), Self::Error>
+    where
+        T: ?Sized + Serialize,
+    {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<String, Self::Error> {
+        Ok(self.0.join(","))
+    }
+}
+
+impl ser::SerializeTupleVariant for StringCompound {
+    type Ok = String;
+    type Error = crate::Error;
+
+    fn serialize_field<T>(&mut self, value: &T) -> Result<(), Self::Error>
+    where
+        T: ?Sized + Serialize,
+    {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<String, Self::Error> {
+        Ok(self.0.join(","))
+    }
+}
+
+impl ser::SerializeMap for StringCompound {
+    type Ok = String;
+    type Error = crate::Error;
+
+    fn serialize_key<T>(&mut self, _key: &T) -> Result<(), Self::Error>
+    where
+        T: ?Sized + Serialize,
+    {
+        Ok(())
+    }
+
+    fn serialize_value<T>(&mut self, value: &T) -> Result<(), Self::Error>
+    where
+        T: ?Sized + Serialize,
+    {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<String, Self::Error> {
+        Ok(self.0.join(","))
+    }
+}
+
+impl ser::SerializeStruct for StringCompound {
+    type Ok = String;
+    type Error = crate::Error;
+
+    fn serialize_field<T>(&mut self, key: &'static str, value: &T) -> Result<(), Self::Error>
+    where
+        T: ?Sized + Serialize,
+    {
+        self.push_field(key, value)
+    }
+
+    fn end(self) -> Result<String, Self::Error> {
+        Ok(self.0.join(","))
+    }
+}
+
+impl ser::SerializeStructVariant for StringCompound {
+    type Ok = String;
+    type Error = crate::Error;
+
+    fn serialize_field<T>(&mut self, key: &'static str, value: &T) -> Result<(), Self::Error>
+    where
+        T: ?Sized + Serialize,
+    {
+        self.push_field(key, value)
+    }
+
+    fn end(self) -> Result<String, Self::Error> {
+        Ok(self.0.join(","))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[derive(Serialize)]
+    struct Foo {
+        #[serde(rename = "{urn:foo}foo:bar")]
+        bar: Bar,
+    }
+
+    #[derive(Serialize)]
+    struct Bar {
+        #[serde(rename = "$attr:id")]
+        id: u32,
+        #[serde(rename = "{urn:foo}foo:baz")]
+        baz: Vec<String>,
+        #[serde(rename = "$attr:late")]
+        late: String,
+    }
+
+    #[test]
+    fn serialize_to_writer() {
+        let value = Foo {
+            bar: Bar {
+                id: 1,
+                baz: vec!["a".to_string(), "b".to_string()],
+                late: "x".to_string(),
+            },
+        };
+
+        let mut output = Vec::new();
+        crate::to_writer(&mut output, &value, crate::Options::default()).unwrap();
+        let output = String::from_utf8(output).unwrap();
+
+        assert_eq!(output, crate::to_string(&value).unwrap());
+        assert_eq!(
+            output,
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<foo:bar xmlns:foo="urn:foo" xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance" xsi:schemaLocation="urn:foo foo.xsd" id="1" late="x">
+  <foo:baz>a</foo:baz>
+  <foo:baz>b</foo:baz>
+</foo:bar>"#
+        );
+    }
+
+    #[test]
+    fn serialize_with_formatting_options() {
+        #[derive(Serialize)]
+        struct Foo {
+            #[serde(rename = "foo")]
+            foo: Bar,
+        }
+
+        #[derive(Serialize)]
+        struct Bar {
+            #[serde(rename = "a")]
+            a: String,
+            #[serde(rename = "b")]
+            b: Baz,
+        }
+
+        #[derive(Serialize)]
+        struct Baz {}
+
+        let value = Foo {
+            foo: Bar {
+                a: "a".to_string(),
+                b: Baz {},
+            },
+        };
+
+        let options = || crate::Options {
+            include_schema_location: false,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            crate::to_string_custom(
+                &value,
+                crate::Options {
+                    perform_indent: false,
+                    write_document_declaration: false,
+                    self_closing: crate::SelfClosing::Padded,
+                    ..options()
+                }
+            )
+            .unwrap(),
+            "<foo><a>a</a><b /></foo>"
+        );
+        assert_eq!(
+            crate::to_string_custom(
+                &value,
+                crate::Options {
+                    indent_string: "\t".into(),
+                    line_separator: "\r\n".into(),
+                    version: xml::common::XmlVersion::Version11,
+                    encoding: "utf-8".into(),
+                    standalone: Some(true),
+                    self_closing: crate::SelfClosing::Expanded,
+                    ..options()
+                }
+            )
+            .unwrap(),
+            "<?xml version=\"1.1\" encoding=\"utf-8\" standalone=\"yes\"?>\r\n<foo>\r\n\t<a>a</a>\r\n\t<b></b>\r\n</foo>"
+        );
+    }
+
+    #[test]
+    fn serialize_empty_values() {
+        #[derive(Serialize)]
+        struct Foo {
+            #[serde(rename = "foo")]
+            foo: Bar,
+        }
+
+        #[derive(Serialize)]
+        struct Bar {
+            #[serde(rename = "a")]
+            a: String,
+            #[serde(rename = "b")]
+            b: Option<u32>,
+            #[serde(rename = "c")]
+            c: (),
+        }
+
+        let value = Foo {
+            foo: Bar {
+                a: String::new(),
+                b: None,
+                c: (),
+            },
+        };
+
+        for (self_closing, xml) in [
+            (crate::SelfClosing::Compact, "<foo><a/><b/><c/></foo>"),
+            (crate::SelfClosing::Padded, "<foo><a /><b /><c /></foo>"),
+            (
+                crate::SelfClosing::Expanded,
+                "<foo><a></a><b></b><c></c></foo>",
+            ),
+        ] {
+            let options = crate::Options {
+                include_schema_location: false,
+                perform_indent: false,
+                write_document_declaration: false,
+                none_elements: crate::NoneElements::Empty,
+                self_closing,
+                ..Default::default()
+            };
+            assert_eq!(crate::to_string_custom(&value, options).unwrap(), xml);
+        }
+    }
+
+    #[test]
+    fn serialize_escaped_attributes() {
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Foo {
+            #[serde(rename = "foo")]
+            foo: Bar,
+        }
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Bar {
+            #[serde(rename = "$attr:a")]
+            a: String,
+            #[serde(rename = "$value")]
+            value: String,
+        }
+
+        let value = Foo {
+            foo: Bar {
+                a: "<&\"'\n é>".to_string(),
+                value: "<&\"' é>".to_string(),
+            },
+        };
+
+        let to_string = |escaping| {
+            crate::to_string_custom(
+                &value,
+                crate::Options {
+                    include_schema_location: false,
+                    write_document_declaration: false,
+                    escaping,
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+        };
+
+        assert_eq!(
+            to_string(crate::Escaping::Minimal),
+            "<foo a=\"&lt;&amp;&quot;'&#xA; é&gt;\">&lt;&amp;\"' é&gt;</foo>"
+        );
+        assert_eq!(
+            to_string(crate::Escaping::All),
+            "<foo a=\"&lt;&amp;&quot;&apos;&#xA; é&gt;\">&lt;&amp;&quot;&apos; é&gt;</foo>"
+        );
+        assert_eq!(
+            to_string(crate::Escaping::NonAsciiNumeric),
+            "<foo a=\"&lt;&amp;&quot;'&#xA; &#xE9;&gt;\">&lt;&amp;\"' &#xE9;&gt;</foo>"
+        );
+
+        // Events carry the values themselves, which the reader would have decoded
+        let events = crate::to_events(&value).unwrap();
+        match &events[..] {
+            [xml::reader::XmlEvent::StartElement { attributes, .. }, xml::reader::XmlEvent::Characters(text), ..] =>
+            {
+                assert_eq!(attributes[0].value, "<&\"'\n é>");
+                assert_eq!(text, "<&\"' é>");
+            }
+            e => panic!("unexpected events {:?}", e),
+        }
+        assert_eq!(crate::from_event_iter::<Foo, _>(events.into_iter().map(Ok)).unwrap(), value);
+    }
+
+    #[test]
+    fn serialize_with_hoisted_namespaces() {
+        #[derive(Serialize)]
+        struct Foo {
+            #[serde(rename = "{urn:foo}foo:foo")]
+            foo: Bar,
+        }
+
+        #[derive(Serialize)]
+        struct Bar {
+            #[serde(rename = "{urn:foo}foo:bar")]
+            bar: Vec<String>,
+            #[serde(rename = "{urn:baz}baz")]
+            baz: Baz,
+            #[serde(rename = "{urn:qux}qux")]
+            qux: String,
+        }
+
+        #[derive(Serialize)]
+        struct Baz {
+            #[serde(rename = "$attr:{urn:attr}a:a")]
+            a: String,
+            #[serde(rename = "{urn:foo}foo:bar")]
+            bar: String,
+        }
+
+        let value = Foo {
+            foo: Bar {
+                bar: vec!["a".to_string(), "b".to_string()],
+                baz: Baz {
+                    a: "a".to_string(),
+                    bar: "c".to_string(),
+                },
+                qux: "d".to_string(),
+            },
+        };
+
+        let to_string = |namespaces| {
+            crate::to_string_custom(
+                &value,
+                crate::Options {
+                    perform_indent: false,
+                    write_document_declaration: false,
+                    namespaces,
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+        };
+
+        assert_eq!(
+            to_string(crate::Namespaces::Minimal),
+            concat!(
+                r#"<foo:foo xmlns:foo="urn:foo" xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance" xsi:schemaLocation="urn:foo foo.xsd">"#,
+                r#"<foo:bar>a</foo:bar><foo:bar>b</foo:bar>"#,
+                r#"<baz xmlns="urn:baz" xmlns:a="urn:attr" xsi:schemaLocation="urn:baz baz.xsd" a:a="a"><foo:bar>c</foo:bar></baz>"#,
+                r#"<qux xmlns="urn:qux" xsi:schemaLocation="urn:qux qux.xsd">d</qux>"#,
+                r#"</foo:foo>"#
+            )
+        );
+        assert_eq!(
+            to_string(crate::Namespaces::Hoisted),
+            concat!(
+                r#"<foo:foo xmlns:a="urn:attr" xmlns:foo="urn:foo" xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance" xsi:schemaLocation="urn:foo foo.xsd">"#,
+                r#"<foo:bar>a</foo:bar><foo:bar>b</foo:bar>"#,
+                r#"<baz xmlns="urn:baz" xsi:schemaLocation="urn:baz baz.xsd" a:a="a"><foo:bar>c</foo:bar></baz>"#,
+                r#"<qux xmlns="urn:qux" xsi:schemaLocation="urn:qux qux.xsd">d</qux>"#,
+                r#"</foo:foo>"#
+            )
+        );
+    }
+
+    #[test]
+    fn serialize_with_schema_locations() {
+        #[derive(Serialize)]
+        struct Foo {
+            #[serde(rename = "{urn:foo}foo:foo")]
+            foo: Bar,
+        }
+
+        #[derive(Serialize)]
+        struct Bar {
+            #[serde(rename = "{urn:bar;bar-1.0.xsd}bar:bar")]
+            bar: String,
+            #[serde(rename = "{urn:baz}baz:baz")]
+            baz: String,
+        }
+
+        let value = Foo {
+            foo: Bar {
+                bar: "a".to_string(),
+                baz: "b".to_string(),
+            },
+        };
+
+        let options = || {
+            let mut options = crate::Options {
+                perform_indent: false,
+                write_document_declaration: false,
+                guess_schema_locations: false,
+                ..Default::default()
+            };
+            options.schema_locations.insert(
+                "urn:foo".to_string(),
+                "http://example.com/foo.xsd".to_string(),
+            );
+            options
+        };
+
+        assert_eq!(
+            crate::to_string_custom(&value, options()).unwrap(),
+            concat!(
+                r#"<foo:foo xmlns:foo="urn:foo" xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance" xsi:schemaLocation="urn:foo http://example.com/foo.xsd">"#,
+                r#"<bar:bar xmlns:bar="urn:bar" xsi:schemaLocation="urn:bar bar-1.0.xsd">a</bar:bar>"#,
+                r#"<baz:baz xmlns:baz="urn:baz">b</baz:baz>"#,
+                r#"</foo:foo>"#
+            )
+        );
+        assert_eq!(
+            crate::to_string_custom(
+                &value,
+                crate::Options {
+                    root_schema_location: true,
+                    no_namespace_schema_location: Some("none.xsd".to_string()),
+                    namespaces: crate::Namespaces::Minimal,
+                    ..options()
+                }
+            )
+            .unwrap(),
+            concat!(
+                r#"<foo:foo xmlns:foo="urn:foo" xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance" xsi:schemaLocation="urn:foo http://example.com/foo.xsd urn:bar bar-1.0.xsd" xsi:noNamespaceSchemaLocation="none.xsd">"#,
+                r#"<bar:bar xmlns:bar="urn:bar">a</bar:bar>"#,
+                r#"<baz:baz xmlns:baz="urn:baz">b</baz:baz>"#,
+                r#"</foo:foo>"#
+            )
+        );
+    }
+
+    #[test]
+    fn serialize_binary_data() {
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Foo {
+            #[serde(rename = "$base64:$attr:digest", with = "serde_bytes")]
+            digest: Vec<u8>,
+            #[serde(rename = "{urn:foo}foo:hex", with = "serde_bytes")]
+            hex: Vec<u8>,
+            #[serde(rename = "$base64:{urn:foo}foo:data", with = "serde_bytes")]
+            data: Vec<u8>,
+        }
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Doc {
+            #[serde(rename = "{urn:foo}foo:foo")]
+            foo: Foo,
+        }
+
+        let value = Doc {
+            foo: Foo {
+                digest: b"xml".to_vec(),
+                hex: vec![0xde, 0xad, 0xbe, 0xef],
+                data: b"hello".to_vec(),
+            },
+        };
+        let xml = concat!(
+            r#"<foo:foo xmlns:foo="urn:foo" digest="eG1s">"#,
+            r#"<foo:hex>deadbeef</foo:hex>"#,
+            r#"<foo:data>aGVsbG8=</foo:data>"#,
+            r#"</foo:foo>"#
+        );
+
+        let options = || crate::Options {
+            include_schema_location: false,
+            perform_indent: false,
+            write_document_declaration: false,
+            ..Default::default()
+        };
+        assert_eq!(crate::to_string_custom(&value, options()).unwrap(), xml);
+        assert_eq!(crate::from_str::<Doc>(xml).unwrap(), value);
+
+        let options = crate::Options {
+            binary_encoding: crate::BinaryEncoding::Base64,
+            ..options()
+        };
+        assert!(crate::to_string_custom(&value, options)
+            .unwrap()
+            .contains("<foo:hex>3q2+7w==</foo:hex>"));
+    }
+
+    #[test]
+    fn serialize_none_elements() {
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Foo {
+            #[serde(rename = "{urn:foo}foo:a", default)]
+            a: Option<String>,
+            #[serde(rename = "{urn:foo}foo:b")]
+            b: Option<u32>,
+        }
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Doc {
+            #[serde(rename = "{urn:foo}foo:foo")]
+            foo: Foo,
+        }
+
+        let value = Doc {
+            foo: Foo {
+                a: None,
+                b: Some(1),
+            },
+        };
+        let options = |none_elements| crate::Options {
+            include_schema_location: false,
+            perform_indent: false,
+            write_document_declaration: false,
+            none_elements,
+            ..Default::default()
+        };
+
+        for (none_elements, xml) in [
+            (
+                crate::NoneElements::Empty,
+                r#"<foo:foo xmlns:foo="urn:foo"><foo:a/><foo:b>1</foo:b></foo:foo>"#,
+            ),
+            (
+                crate::NoneElements::Omit,
+                r#"<foo:foo xmlns:foo="urn:foo"><foo:b>1</foo:b></foo:foo>"#,
+            ),
+            (
+                crate::NoneElements::Nil,
+                r#"<foo:foo xmlns:foo="urn:foo"><foo:a xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance" xsi:nil="true"/><foo:b>1</foo:b></foo:foo>"#,
+            ),
+        ] {
+            let output = crate::to_string_custom(&value, options(none_elements)).unwrap();
+            assert_eq!(output, xml);
+            assert_eq!(crate::from_str::<Doc>(&output).unwrap(), value);
+        }
+
+        assert_eq!(
+            crate::from_str::<Doc>(
+                r#"<foo xmlns="urn:foo" xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance">
+<a xsi:nil="true" id="1"/><b xsi:nil="1"></b></foo>"#
+            )
+            .unwrap(),
+            Doc {
+                foo: Foo { a: None, b: None }
+            }
+        );
+    }
+
+    #[test]
+    fn serialize_xsi_type_enum() {
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Address {
+            #[serde(rename = "$attr:category")]
+            category: String,
+            #[serde(rename = "$value")]
+            value: String,
+        }
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        enum Observable {
+            #[serde(rename = "$type:{urn:iodef}iodef:AddressType")]
+            Address(Address),
+            #[serde(rename = "$type:{urn:iodef}iodef:EmailType")]
+            Email {
+                #[serde(rename = "{urn:iodef}iodef:EmailAddress")]
+                address: String,
+            },
+            #[serde(rename = "$type:{urn:iodef}iodef:UnknownType")]
+            Unknown,
+        }
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct List {
+            #[serde(rename = "{urn:iodef}iodef:Observable")]
+            observables: Vec<Observable>,
+        }
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Doc {
+            #[serde(rename = "{urn:iodef}iodef:List")]
+            list: List,
+        }
+
+        let value = Doc {
+            list: List {
+                observables: vec![
+                    Observable::Address(Address {
+                        category: "ipv4-addr".to_string(),
+                        value: "192.0.2.1".to_string(),
+                    }),
+                    Observable::Email {
+                        address: "a@example.com".to_string(),
+                    },
+                    Observable::Unknown,
+                ],
+            },
+        };
+        let options = crate::Options {
+            include_schema_location: false,
+            perform_indent: false,
+            write_document_declaration: false,
+            namespaces: crate::Namespaces::Minimal,
+            ..Default::default()
+        };
+
+        let xml = crate::to_string_custom(&value, options).unwrap();
+        assert_eq!(
+            xml,
+            concat!(
+                r#"<iodef:List xmlns:iodef="urn:iodef">"#,
+                r#"<iodef:Observable xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance" category="ipv4-addr" xsi:type="iodef:AddressType">192.0.2.1</iodef:Observable>"#,
+                r#"<iodef:Observable xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance" xsi:type="iodef:EmailType"><iodef:EmailAddress>a@example.com</iodef:EmailAddress></iodef:Observable>"#,
+                r#"<iodef:Observable xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance" xsi:type="iodef:UnknownType"/>"#,
+                r#"</iodef:List>"#
+            )
+        );
+        assert_eq!(crate::from_str::<Doc>(&xml).unwrap(), value);
+
+        assert_eq!(
+            crate::from_str::<Doc>(
+                r#"<List xmlns="urn:iodef" xmlns:t="urn:iodef" xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance">
+<Observable xsi:type="t:UnknownType"/></List>"#
+            )
+            .unwrap(),
+            Doc {
+                list: List {
+                    observables: vec![Observable::Unknown]
+                }
+            }
+        );
+        assert!(crate::from_str::<Doc>(
+            r#"<List xmlns="urn:iodef" xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance">
+<Observable xsi:type="UnknownType"/></List>"#
+        )
+        .is_ok());
+        assert!(crate::from_str::<Doc>(
+            r#"<List xmlns="urn:iodef" xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance">
+<Observable xsi:type="OtherType"/></List>"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn serialize_xsi_type_without_prefix() {
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        enum Shape {
+            #[serde(rename = "$type:{urn:shapes}Circle")]
+            Circle {
+                #[serde(rename = "{urn:doc}radius")]
+                radius: u32,
+            },
+            #[serde(rename = "$type:Square")]
+            Square {
+                #[serde(rename = "{urn:doc}side")]
+                side: u32,
+            },
+        }
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Doc {
+            #[serde(rename = "{urn:doc}shape")]
+            shape: Shape,
+        }
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Nested {
+            #[serde(rename = "{urn:doc}doc")]
+            doc: Doc,
+        }
+
+        let value = Doc {
+            shape: Shape::Circle { radius: 2 },
+        };
+        for namespaces in [
+            crate::Namespaces::PerElement,
+            crate::Namespaces::Minimal,
+            crate::Namespaces::Hoisted,
+        ] {
+            let options = crate::Options {
+                include_schema_location: false,
+                perform_indent: false,
+                write_document_declaration: false,
+                namespaces,
+                ..Default::default()
+            };
+            let xml = crate::to_string_custom(&value, options.clone()).unwrap();
+            assert!(xml.contains(r#"xmlns="urn:doc""#), "{}", xml);
+            assert!(xml.contains(r#"xmlns:ns1="urn:shapes""#), "{}", xml);
+            assert!(xml.contains(r#"xsi:type="ns1:Circle""#), "{}", xml);
+            assert!(!xml.contains(r#"xmlns="urn:shapes""#), "{}", xml);
+            assert_eq!(crate::from_str::<Doc>(&xml).unwrap(), value);
+
+            // An unprefixed type name would be read in the element's default namespace
+            let square = Doc {
+                shape: Shape::Square { side: 3 },
+            };
+            let xml = crate::to_string_custom(&square, options.clone()).unwrap();
+            assert!(xml.contains(r#"<ns1:shape xmlns:ns1="urn:doc""#), "{}", xml);
+            assert!(xml.contains(r#"xsi:type="Square""#), "{}", xml);
+            assert!(xml.contains(r#"<side xmlns="urn:doc">3</side>"#), "{}", xml);
+            assert_eq!(crate::from_str::<Doc>(&xml).unwrap(), square);
+
+            // The writer cannot unset a default namespace set further up
+            let nested = Nested { doc: square };
+            assert!(crate::to_string_custom(&nested, options).is_err());
+        }
+    }
+
+    #[test]
+    fn serialize_mixed_content() {
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Link {
+            #[serde(rename = "$attr:href")]
+            href: String,
+            #[serde(rename = "$value")]
+            text: String,
+        }
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        enum Inline {
+            #[serde(rename = "$text")]
+            Text(String),
+            #[serde(rename = "b")]
+            Bold(String),
+            #[serde(rename = "a")]
+            Link(Link),
+        }
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Paragraph {
+            #[serde(rename = "$attr:class")]
+            class: String,
+            #[serde(rename = "$mixed")]
+            content: Vec<Inline>,
+        }
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Doc {
+            #[serde(rename = "p")]
+            p: Paragraph,
+        }
+
+        let xml =
+            r#"<p class="intro">Hello<b>world</b>, see<a href="https://example.com">here</a>!</p>"#;
+        let value = Doc {
+            p: Paragraph {
+                class: "intro".to_string(),
+                content: vec![
+                    Inline::Text("Hello".to_string()),
+                    Inline::Bold("world".to_string()),
+                    Inline::Text(", see".to_string()),
+                    Inline::Link(Link {
+                        href: "https://example.com".to_string(),
+                        text: "here".to_string(),
+                    }),
+                    Inline::Text("!".to_string()),
+                ],
+            },
+        };
+
+        assert_eq!(crate::from_str::<Doc>(xml).unwrap(), value);
+
+        let options = crate::Options {
+            include_schema_location: false,
+            perform_indent: false,
+            write_document_declaration: false,
+            ..Default::default()
+        };
+        assert_eq!(crate::to_string_custom(&value, options).unwrap(), xml);
+    }
+
+    #[test]
+    fn serialize_preserved_whitespace() {
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Text {
+            #[serde(rename = "$attr:xml:space")]
+            space: String,
+            #[serde(rename = "$value")]
+            text: String,
+        }
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Listing {
+            #[serde(rename = "$attr:xml:space")]
+            space: String,
+            line: Vec<String>,
+        }
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Login {
+            user: String,
+            pw: Text,
+            listing: Listing,
+        }
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Doc {
+            login: Login,
+        }
+
+        let xml = r#"<login>
+  <user>bob</user>
+  <pw xml:space="preserve">  secret </pw>
+  <listing xml:space="preserve"><line>fn main() {</line><line>    println!();</line><line>}</line></listing>
+</login>"#;
+        let value = Doc {
+            login: Login {
+                user: "bob".to_string(),
+                pw: Text {
+                    space: "preserve".to_string(),
+                    text: "  secret ".to_string(),
+                },
+                listing: Listing {
+                    space: "preserve".to_string(),
+                    line: vec![
+                        "fn main() {".to_string(),
+                        "    println!();".to_string(),
+                        "}".to_string(),
+                    ],
+                },
+            },
+        };
+
+        let options = crate::Options {
+            include_schema_location: false,
+            write_document_declaration: false,
+            ..Default::default()
+        };
+        assert_eq!(crate::to_string_custom(&value, options).unwrap(), xml);
+        assert_eq!(crate::from_str::<Doc>(xml).unwrap(), value);
+
+        let xml = r#"<login>
+  <user> bob </user>
+  <pw xml:space="preserve">   </pw>
+  <listing xml:space="preserve">
+    <line> a </line>
+    <line xml:space="default"> b </line>
+  </listing>
+</login>"#;
+        let login = crate::from_str::<Doc>(xml).unwrap().login;
+        assert_eq!(login.user, "bob");
+        assert_eq!(login.pw.text, "   ");
+        assert_eq!(login.listing.line, vec![" a ".to_string(), "b".to_string()]);
+    }
+
+    #[test]
+    fn serialize_raw_xml() {
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Envelope {
+            #[serde(rename = "{urn:e}e:header")]
+            header: String,
+            #[serde(rename = "{urn:e}e:body")]
+            body: crate::RawXml,
+        }
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Doc {
+            #[serde(rename = "{urn:e}e:envelope")]
+            envelope: Envelope,
+        }
+
+        let xml = r#"<e:envelope xmlns:e="urn:e" xmlns:x="urn:x"><e:header>h</e:header><e:body a='1'>
+  <x:item x:id="2"> a &amp; b </x:item>
+  <!-- c --><?pi d?>
+</e:body></e:envelope>"#;
+        let doc = crate::from_str::<Doc>(xml).unwrap();
+        let body = r#"<e:body xmlns:e="urn:e" xmlns:x="urn:x" a="1">
+  <x:item x:id="2"> a &amp; b </x:item>
+  <!-- c --><?pi d?>
+</e:body>"#;
+        assert_eq!(doc.envelope.body.as_str(), body);
+
+        let options = crate::Options {
+            include_schema_location: false,
+            perform_indent: false,
+            write_document_declaration: false,
+            ..Default::default()
+        };
+        assert_eq!(
+            crate::to_string_custom(&doc, options).unwrap(),
+            format!(
+                r#"<e:envelope xmlns:e="urn:e"><e:header>h</e:header>{}</e:envelope>"#,
+                body
+            )
+        );
+
+        // As events, the markup is broken down into the events it is made of
+        let events = crate::to_events(&doc).unwrap();
+        assert!(events.contains(&xml::reader::XmlEvent::Characters(" a & b ".to_string())));
+        assert!(events.contains(&xml::reader::XmlEvent::Comment(" c ".to_string())));
+        assert!(events.contains(&xml::reader::XmlEvent::ProcessingInstruction {
+            name: "pi".to_string(),
+            data: Some("d".to_string()),
+        }));
+        // Reading from events drops processing instructions
+        let read = crate::from_event_iter::<Doc, _>(events.into_iter().map(Ok)).unwrap();
+        assert_eq!(
+            read.envelope.body.as_str(),
+            body.replace("<?pi d?>", "")
+        );
+    }
+
+    #[test]
+    fn serialize_event_writer() {
+        #[derive(Debug, Serialize)]
+        struct Doc {
+            item: Item,
+        }
+
+        #[derive(Debug, Serialize)]
+        struct Item {
+            #[serde(rename = "$attr:id")]
+            id: u32,
+            name: String,
+        }
+
+        let options = crate::Options {
+            include_schema_location: false,
+            perform_indent: false,
+            ..Default::default()
+        };
+        let mut writer = options.emitter_config().create_writer(vec![]);
+        writer
+            .write(xml::writer::XmlEvent::start_element("envelope"))
+            .unwrap();
+        let doc = Doc {
+            item: Item {
+                id: 1,
+                name: "a & b".to_string(),
+            },
+        };
+        crate::to_event_writer_custom(&mut writer, &doc, options.clone()).unwrap();
+        writer.write(xml::writer::XmlEvent::end_element()).unwrap();
+        assert_eq!(
+            String::from_utf8(writer.into_inner()).unwrap(),
+            r#"<envelope><item id="1"><name>a &amp; b</name></item></envelope>"#
+        );
+
+        #[derive(Debug, Serialize)]
+        struct RawDoc {
+            item: Raw,
+        }
+
+        #[derive(Debug, Serialize)]
+        struct Raw {
+            #[serde(rename = "$attr:title")]
+            title: String,
+            body: crate::RawXml,
+        }
+
+        // A writer as xml-rs sets it up by default, escaping text itself
+        let mut writer = xml::writer::EmitterConfig::new().create_writer(vec![]);
+        writer
+            .write(xml::writer::XmlEvent::start_element("envelope"))
+            .unwrap();
+        crate::to_event_writer_custom(&mut writer, &doc, options.clone()).unwrap();
+        let raw = RawDoc {
+            item: Raw {
+                title: "\"x\" < y".to_string(),
+                body: crate::RawXml::new("<body><b>a &amp; b</b></body>"),
+            },
+        };
+        crate::to_event_writer_custom(&mut writer, &raw, options).unwrap();
+        writer.write(xml::writer::XmlEvent::end_element()).unwrap();
+        assert_eq!(
+            String::from_utf8(writer.into_inner()).unwrap(),
+            r#"<?xml version="1.0" encoding="UTF-8"?><envelope><item id="1"><name>a &amp; b</name></item><item title="&quot;x&quot; &lt; y"><body><b>a &amp; b</b></body></item></envelope>"#
+        );
+    }
+
+    #[test]
+    fn serialize_encodings() {
+        #[derive(Debug, Serialize, Deserialize)]
+        struct Doc {
+            a: String,
+        }
+
+        #[derive(Debug, Serialize)]
+        struct Doc2<T> {
+            a: T,
+        }
+
+        let doc = Doc {
+            a: "caf\u{e9} \u{20ac}".to_string(),
+        };
+        let options = crate::Options {
+            include_schema_location: false,
+            perform_indent: false,
+            encoding: "ISO-8859-2".into(),
+            ..Default::default()
+        };
+        let mut output = vec![];
+        crate::to_writer(&mut output, &doc, options).unwrap();
+        assert_eq!(
+            output,
+            b"<?xml version=\"1.0\" encoding=\"ISO-8859-2\"?><a>caf\xe9 &#x20AC;</a>"
+        );
+
+        // Stateful encodings are brought back to their initial state at the end
+        let options = crate::Options {
+            include_schema_location: false,
+            perform_indent: false,
+            encoding: "ISO-2022-JP".into(),
+            ..Default::default()
+        };
+        let doc = Doc {
+            a: "\u{65e5}\u{672c} \u{20ac}".to_string(),
+        };
+        let mut output = vec![];
+        crate::to_writer(&mut output, &doc, options.clone()).unwrap();
+        assert!(output.ends_with(b"\x1b$BF|K\\\x1b(B &#x20AC;</a>"));
+        assert_eq!(crate::from_bytes::<Doc>(&output).unwrap().a, doc.a);
+        let mut writer = crate::FragmentWriter::new(vec![], options).unwrap();
+        writer.open_root("\u{65e5}", &()).unwrap();
+        assert!(writer.into_inner().ends_with(b"<\x1b$BF|\x1b(B>"));
+
+        let options = crate::Options {
+            include_schema_location: false,
+            perform_indent: false,
+            encoding: "UTF-16LE".into(),
+            ..Default::default()
+        };
+        let mut output = vec![];
+        crate::to_writer(&mut output, &doc, options).unwrap();
+        assert_eq!(output[..2], [0xFF, 0xFE]);
+        assert_eq!(crate::from_bytes::<Doc>(&output).unwrap().a, doc.a);
+
+        let options = crate::Options {
+            include_schema_location: false,
+            perform_indent: false,
+            encoding: "ISO-8859-1".into(),
+            ..Default::default()
+        };
+        let doc = Doc {
+            a: "caf\u{e9} \u{80} \u{20ac}".to_string(),
+        };
+        let mut output = vec![];
+        crate::to_writer(&mut output, &doc, options.clone()).unwrap();
+        assert_eq!(
+            output,
+            b"<?xml version=\"1.0\" encoding=\"ISO-8859-1\"?><a>caf\xe9 \x80 &#x20AC;</a>"
+        );
+        assert_eq!(crate::from_bytes::<Doc>(&output).unwrap().a, doc.a);
+        assert_eq!(
+            crate::to_string_custom(&doc, options.clone()).unwrap(),
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?><a>caf\u{e9} \u{80} \u{20ac}</a>"
+        );
+
+        // Anywhere but text and attribute values, a character the encoding lacks is an error
+        #[derive(Debug, Serialize)]
+        struct Euro {
+            #[serde(rename = "$attr:sign")]
+            sign: String,
+            #[serde(rename = "\u{20ac}", skip_serializing_if = "Option::is_none")]
+            euro: Option<String>,
+        }
+
+        let euro = |name: Option<&str>| Euro {
+            sign: "\u{20ac}".to_string(),
+            euro: name.map(str::to_string),
+        };
+        let mut output = vec![];
+        crate::to_writer(&mut output, &Doc2 { a: euro(None) }, options.clone()).unwrap();
+        assert!(output.ends_with(b"<a sign=\"&#x20AC;\"/>"));
+        assert!(matches!(
+            crate::to_writer(vec![], &Doc2 { a: euro(Some("1")) }, options),
+            Err(crate::Error::XMLWError(_))
+        ));
+
+        let options = crate::Options {
+            encoding: "EBCDIC".into(),
+            ..Default::default()
+        };
+        assert!(matches!(
+            crate::to_writer(vec![], &doc, options),
+            Err(crate::Error::UnknownEncoding { label }) if label == "EBCDIC"
+        ));
+    }
+
+    #[test]
+    fn serialize_fragments() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        #[derive(Debug, Serialize)]
+        struct Stream {
+            #[serde(rename = "$attr:to")]
+            to: String,
+        }
+
+        #[derive(Debug, Serialize)]
+        struct Stanza {
+            #[serde(rename = "{jabber:client}message")]
+            message: Message,
+        }
+
+        #[derive(Debug, Serialize)]
+        struct Message {
+            #[serde(rename = "$attr:to")]
+            to: String,
+            #[serde(rename = "{jabber:client}body")]
+            body: String,
+        }
+
+        /// Output shared with the test, to see what has been sent so far, and how much of it had
+        /// been written at each flush
+        #[derive(Clone, Default)]
+        struct Sent(Rc<RefCell<Vec<u8>>>, Rc<RefCell<Vec<usize>>>);
+
+        impl std::io::Write for Sent {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.borrow_mut().write(buf)
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                self.1.borrow_mut().push(self.0.borrow().len());
+                Ok(())
+            }
+        }
+
+        let stanza = |to: &str, body: &str| Stanza {
+            message: Message {
+                to: to.to_string(),
+                body: body.to_string(),
+            },
+        };
+        let sent = Sent::default();
+        let output = || String::from_utf8(sent.0.borrow().clone()).unwrap();
+        let flushed = || sent.1.borrow().last().copied();
+        let options = crate::Options {
+            include_schema_location: false,
+            perform_indent: false,
+            ..Default::default()
+        };
+        let mut writer = crate::FragmentWriter::new(sent.clone(), options).unwrap();
+        writer
+            .open_root(
+                "{http://etherx.jabber.org/streams}stream:stream",
+                &Stream {
+                    to: "example.com".to_string(),
+                },
+            )
+            .unwrap();
+        assert_eq!(
+            output(),
+            r#"<?xml version="1.0" encoding="UTF-8"?><stream:stream xmlns:stream="http://etherx.jabber.org/streams" to="example.com">"#
+        );
+        assert_eq!(flushed(), Some(output().len()));
+        writer.write(&stanza("a", "hi")).unwrap();
+        assert!(output().ends_with("</message>"));
+        assert_eq!(flushed(), Some(output().len()));
+        writer.write(&stanza("b", "bye")).unwrap();
+        writer.close_root().unwrap();
+        assert_eq!(flushed(), Some(output().len()));
+        assert_eq!(
+            output(),
+            r#"<?xml version="1.0" encoding="UTF-8"?><stream:stream xmlns:stream="http://etherx.jabber.org/streams" to="example.com"><message xmlns="jabber:client" to="a"><body>hi</body></message><message xmlns="jabber:client" to="b"><body>bye</body></message></stream:stream>"#
+        );
+        assert!(writer.close_root().is_err());
+
+        let options = crate::Options {
+            include_schema_location: false,
+            write_document_declaration: false,
+            ..Default::default()
+        };
+        let mut writer = crate::FragmentWriter::new(vec![], options).unwrap();
+        writer.open_root("export", &()).unwrap();
+        writer.write(&stanza("a", "hi")).unwrap();
+        writer.write(&stanza("b", "bye")).unwrap();
+        writer.close_root().unwrap();
+        assert_eq!(
+            String::from_utf8(writer.into_inner()).unwrap(),
+            "<export>\n  <message xmlns=\"jabber:client\" to=\"a\">\n    <body>hi</body>\n  </message>\n  <message xmlns=\"jabber:client\" to=\"b\">\n    <body>bye</body>\n  </message>\n</export>"
+        );
+
+        // A root with nothing in it gets no indentation inside
+        for perform_indent in [true, false] {
+            let options = crate::Options {
+                include_schema_location: false,
+                write_document_declaration: false,
+                perform_indent,
+                ..Default::default()
+            };
+            let mut writer = crate::FragmentWriter::new(vec![], options).unwrap();
+            writer.open_root("export", &()).unwrap();
+            writer.close_root().unwrap();
+            assert_eq!(
+                String::from_utf8(writer.into_inner()).unwrap(),
+                "<export></export>"
+            );
+        }
+
+        // What goes on the root depends on values not yet written
+        let options = crate::Options {
+            namespaces: crate::Namespaces::Hoisted,
+            ..Default::default()
+        };
+        assert!(matches!(
+            crate::FragmentWriter::new(vec![], options),
+            Err(crate::Error::Message(_))
+        ));
+        let options = crate::Options {
+            root_schema_location: true,
+            ..Default::default()
+        };
+        assert!(matches!(
+            crate::FragmentWriter::new(vec![], options),
+            Err(crate::Error::Message(_))
+        ));
     }
 }
@@ -23,6 +23,7 @@ impl<'a> Tag<'a> {
         }
     }
 
+    #[allow(clippy::ptr_arg)]
     pub fn from_cow(str: &'a Cow<'static, str>) -> Tag<'a> {
         match str {
             Cow::Borrowed(str) => Tag::from_static(str),
@@ -38,14 +39,15 @@ impl Tag<'static> {
         use std::sync::Mutex;
 
         // Make a single global BTreeMap to act as a cache
-        static CACHE: OnceCell<Mutex<BTreeMap<usize, Tag<'static>>>> = OnceCell::new();
+        static CACHE: OnceCell<Mutex<BTreeMap<(usize, usize), Tag<'static>>>> = OnceCell::new();
         let mut cache = CACHE
             .get_or_init(|| Mutex::new(BTreeMap::new()))
             .lock()
             .unwrap();
 
-        // Look up the pointer address of our &'static [&'static str] in the cache
-        match cache.entry(str.as_ptr() as usize) {
+        // Look up the pointer address and length of our &'static str in the cache, slices
+        // with a stripped suffix share their address with the full string
+        match cache.entry((str.as_ptr() as usize, str.len())) {
             Entry::Vacant(e) => {
                 // Miss
                 *e.insert(Self::new(str))